@@ -26,25 +26,18 @@ fn main() {
         })
         .collect();
 
-    // construct named_entities.rs
+    // construct named_entities.rs, a static table of (name, characters)
     let mut named_entities_rs = String::new();
-    named_entities_rs.push_str(
-        "use std::collections::HashMap;
-    pub fn get_named_entities() -> HashMap<String, String> {
-        HashMap::from([\n",
-    );
+    named_entities_rs.push_str("pub static NAMED_ENTITIES: &[(&str, &str)] = &[\n");
     for (name, chars) in named_entities {
         let name = &name[1..name.len() - 1];
         named_entities_rs.push_str(&format!(
-            "    (\"{}\".to_string(), \"{}\".to_string()),\n",
+            "    (\"{}\", \"{}\"),\n",
             name,
             chars.escape_unicode()
         ))
     }
-    named_entities_rs.push_str(
-        "])
-    }",
-    );
+    named_entities_rs.push_str("];\n");
 
     let out_dir = env::var_os("OUT_DIR").unwrap();
     let dest_path = Path::new(&out_dir).join("entities.rs");
@@ -0,0 +1,722 @@
+use crate::types::{Block, Inline};
+use crate::util::{is_collapsible_whitespace, ContentHandler, PendingWhitespace};
+
+#[derive(Clone, Copy)]
+enum TextBlockKind {
+    Paragraph,
+    Heading(u8),
+    Preformatted,
+}
+
+#[derive(Clone)]
+enum InlineKind {
+    Emphasis,
+    Strong,
+    Link(String),
+}
+
+#[derive(Clone, Copy)]
+enum ContainerKind {
+    Quote,
+    List { ordered: bool },
+    ListItem,
+    Table,
+    Row,
+    Cell,
+}
+
+// block-level elements that contain other blocks
+enum Container {
+    Root(Vec<Block>),
+    Quote(Vec<Block>),
+    List {
+        ordered: bool,
+        items: Vec<Vec<Block>>,
+    },
+    ListItem(Vec<Block>),
+    Table(Vec<Vec<Vec<Inline>>>),
+    Row(Vec<Vec<Inline>>),
+    Cell(Vec<Inline>),
+}
+
+impl Container {
+    fn new(kind: ContainerKind) -> Self {
+        match kind {
+            ContainerKind::Quote => Container::Quote(Vec::new()),
+            ContainerKind::List { ordered } => Container::List {
+                ordered,
+                items: Vec::new(),
+            },
+            ContainerKind::ListItem => Container::ListItem(Vec::new()),
+            ContainerKind::Table => Container::Table(Vec::new()),
+            ContainerKind::Row => Container::Row(Vec::new()),
+            ContainerKind::Cell => Container::Cell(Vec::new()),
+        }
+    }
+}
+
+// how an html element affects the block structure
+#[derive(Clone)]
+enum Role {
+    // content is not part of the text (e.g. <head>, <script>)
+    Skip,
+    // contains inline content only (e.g. <p>, <h1>)
+    TextBlock(TextBlockKind),
+    // contains blocks (e.g. <blockquote>, <ul>)
+    Container(ContainerKind),
+    // formatted inline content (e.g. <em>, <a>)
+    Inline(InlineKind),
+    LineBreak,
+    // other block-level elements that separate blocks (e.g. <div>, <section>)
+    Boundary,
+    // inline elements without formatting (e.g. <span>)
+    Transparent,
+}
+
+fn get_role(element: &xmltree::Element) -> Role {
+    match element.name.as_str() {
+        // ruby annotations (pronunciation) and their fallback parentheses are left out
+        "head" | "script" | "style" | "template" | "rt" | "rp" => Role::Skip,
+        "p" => Role::TextBlock(TextBlockKind::Paragraph),
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+            let level = element.name[1..].parse().unwrap_or(1);
+            Role::TextBlock(TextBlockKind::Heading(level))
+        }
+        "pre" => Role::TextBlock(TextBlockKind::Preformatted),
+        "blockquote" => Role::Container(ContainerKind::Quote),
+        "ul" | "menu" => Role::Container(ContainerKind::List { ordered: false }),
+        "ol" => Role::Container(ContainerKind::List { ordered: true }),
+        "li" => Role::Container(ContainerKind::ListItem),
+        "table" => Role::Container(ContainerKind::Table),
+        "tr" => Role::Container(ContainerKind::Row),
+        "td" | "th" => Role::Container(ContainerKind::Cell),
+        "em" | "i" | "cite" | "dfn" | "var" => Role::Inline(InlineKind::Emphasis),
+        "strong" | "b" => Role::Inline(InlineKind::Strong),
+        "a" => match element.attributes.get("href") {
+            Some(href) => Role::Inline(InlineKind::Link(href.to_string())),
+            None => Role::Transparent,
+        },
+        "br" => Role::LineBreak,
+        "address" | "article" | "aside" | "body" | "caption" | "center" | "dd" | "details"
+        | "dialog" | "div" | "dl" | "dt" | "fieldset" | "figcaption" | "figure" | "footer"
+        | "form" | "header" | "hgroup" | "hr" | "html" | "legend" | "main" | "nav" | "section"
+        | "summary" | "tbody" | "tfoot" | "thead" => Role::Boundary,
+        _ => Role::Transparent,
+    }
+}
+
+/// Whether the element is rendered inline (is not block-level or a line break)
+pub fn is_inline_element(element: &xmltree::Element) -> bool {
+    matches!(get_role(element), Role::Inline(_) | Role::Transparent)
+}
+
+/// Whether the element and its content are not rendered (e.g. `<head>` or `<script>`)
+pub fn is_skipped_element(element: &xmltree::Element) -> bool {
+    matches!(get_role(element), Role::Skip)
+}
+
+fn push_str(content: &mut Vec<Inline>, s: &str) {
+    if let Some(Inline::Text { text }) = content.last_mut() {
+        text.push_str(s);
+    } else {
+        content.push(Inline::Text {
+            text: s.to_string(),
+        });
+    }
+}
+
+// remove trailing whitespace and line breaks at the end of a block
+fn trim_end(content: &mut Vec<Inline>) {
+    while let Some(last) = content.last_mut() {
+        match last {
+            Inline::Text { text } => {
+                let trimmed_len = text.trim_end_matches(is_collapsible_whitespace).len();
+                text.truncate(trimmed_len);
+                if !text.is_empty() {
+                    return;
+                }
+            }
+            Inline::Emphasis { content } | Inline::Strong { content } => {
+                trim_end(content);
+                if !content.is_empty() {
+                    return;
+                }
+            }
+            Inline::Link { content, .. } => {
+                trim_end(content);
+                return;
+            }
+            Inline::LineBreak => {}
+        }
+        content.pop();
+    }
+}
+
+fn wrap_inline(kind: InlineKind, content: Vec<Inline>) -> Option<Inline> {
+    match kind {
+        InlineKind::Link(href) => Some(Inline::Link { href, content }),
+        _ if content.is_empty() => None,
+        InlineKind::Emphasis => Some(Inline::Emphasis { content }),
+        InlineKind::Strong => Some(Inline::Strong { content }),
+    }
+}
+
+// inline content of a block that is nested in a table cell
+fn into_inline_content(block: Block) -> Vec<Inline> {
+    let nested_blocks = match block {
+        Block::Paragraph { content } | Block::Heading { content, .. } => return content,
+        Block::Preformatted { text } => return vec![Inline::Text { text }],
+        Block::Quote { blocks } => blocks,
+        Block::List { items, .. } => items.into_iter().flatten().collect(),
+        Block::Table { rows } => {
+            return rows
+                .into_iter()
+                .flatten()
+                .filter(|cell| !cell.is_empty())
+                .collect::<Vec<_>>()
+                .join(&Inline::LineBreak)
+        }
+    };
+    nested_blocks
+        .into_iter()
+        .map(into_inline_content)
+        .filter(|content| !content.is_empty())
+        .collect::<Vec<_>>()
+        .join(&Inline::LineBreak)
+}
+
+fn inlines_to_text(content: &[Inline], text: &mut String) {
+    for inline in content {
+        match inline {
+            Inline::Text { text: s } => text.push_str(s),
+            Inline::Emphasis { content }
+            | Inline::Strong { content }
+            | Inline::Link { content, .. } => inlines_to_text(content, text),
+            Inline::LineBreak => text.push('\n'),
+        }
+    }
+}
+
+fn collect_paragraphs(blocks: &[Block], paragraphs: &mut Vec<String>) {
+    for block in blocks {
+        match block {
+            Block::Paragraph { content } | Block::Heading { content, .. } => {
+                let mut text = String::new();
+                inlines_to_text(content, &mut text);
+                paragraphs.push(text);
+            }
+            Block::Preformatted { text } => paragraphs.push(text.clone()),
+            Block::Quote { blocks } => collect_paragraphs(blocks, paragraphs),
+            Block::List { items, .. } => items
+                .iter()
+                .for_each(|item| collect_paragraphs(item, paragraphs)),
+            // one line per row, with cells separated by tabs
+            Block::Table { rows } => {
+                let rows: Vec<String> = rows
+                    .iter()
+                    .map(|row| {
+                        row.iter()
+                            .map(|cell| {
+                                let mut text = String::new();
+                                inlines_to_text(cell, &mut text);
+                                text
+                            })
+                            .collect::<Vec<_>>()
+                            .join("\t")
+                    })
+                    .collect();
+                paragraphs.push(rows.join("\n"));
+            }
+        }
+    }
+}
+
+/// Render blocks as text, separating the blocks by blank lines
+pub fn blocks_to_text(blocks: &[Block]) -> String {
+    let mut paragraphs = Vec::new();
+    collect_paragraphs(blocks, &mut paragraphs);
+    paragraphs.join("\n\n")
+}
+
+/// Builds the block structure of an html document from the nodes passed by the html walker
+///
+/// Whitespace is collapsed as done by browsers for the default styles of the html elements.
+pub struct BlockBuilder {
+    containers: Vec<Container>,
+    // roles of the currently open elements
+    open: Vec<Role>,
+    skip_depth: usize,
+    text_block_kinds: Vec<TextBlockKind>,
+    // inline content of the current text block
+    content: Vec<Inline>,
+    // currently open formatted inline elements
+    inlines: Vec<(InlineKind, Vec<Inline>)>,
+    preformatted: String,
+    has_content: bool,
+    pending: PendingWhitespace,
+    // last character on the current line of the text block
+    previous: Option<char>,
+    cjk_language: bool,
+}
+
+impl BlockBuilder {
+    pub fn new(cjk_language: bool) -> Self {
+        BlockBuilder {
+            containers: vec![Container::Root(Vec::new())],
+            open: Vec::new(),
+            skip_depth: 0,
+            text_block_kinds: Vec::new(),
+            content: Vec::new(),
+            inlines: Vec::new(),
+            preformatted: String::new(),
+            has_content: false,
+            pending: PendingWhitespace::None,
+            previous: None,
+            cjk_language,
+        }
+    }
+
+    pub fn finish(mut self) -> Vec<Block> {
+        self.flush();
+        while self.containers.len() > 1 {
+            self.close_container();
+        }
+        match self.containers.pop() {
+            Some(Container::Root(blocks)) => blocks,
+            _ => Vec::new(),
+        }
+    }
+
+    fn text_block_kind(&self) -> TextBlockKind {
+        *self
+            .text_block_kinds
+            .last()
+            .unwrap_or(&TextBlockKind::Paragraph)
+    }
+
+    fn current_content(&mut self) -> &mut Vec<Inline> {
+        match self.inlines.last_mut() {
+            Some((_kind, content)) => content,
+            None => &mut self.content,
+        }
+    }
+
+    // emit the pending whitespace before the next character (if known)
+    fn emit_pending_whitespace(&mut self, next: Option<char>) {
+        let separates = match (self.previous, next) {
+            (Some(previous), Some(next)) => {
+                Some(self.pending.separates(previous, next, self.cjk_language))
+            }
+            (Some(previous), None) => self.pending.separates_any(previous, self.cjk_language),
+            (None, _) => Some(false),
+        };
+        if let Some(separates) = separates {
+            if separates {
+                push_str(self.current_content(), " ");
+            }
+            self.pending = PendingWhitespace::None;
+        }
+    }
+
+    fn open_inline(&mut self, kind: InlineKind) {
+        // whitespace before an inline element belongs to the parent
+        // unless it may be dropped depending on the following text
+        self.emit_pending_whitespace(None);
+        self.inlines.push((kind, Vec::new()));
+    }
+
+    fn close_inline(&mut self) {
+        if let Some((kind, content)) = self.inlines.pop() {
+            if let Some(inline) = wrap_inline(kind, content) {
+                self.current_content().push(inline);
+            }
+        }
+    }
+
+    fn line_break(&mut self) {
+        if let TextBlockKind::Preformatted = self.text_block_kind() {
+            self.preformatted.push('\n');
+        } else if self.has_content {
+            self.pending = PendingWhitespace::None;
+            self.previous = None;
+            self.current_content().push(Inline::LineBreak);
+        }
+    }
+
+    // end the current text block and add it to the current container
+    fn flush(&mut self) {
+        // inline elements may span several blocks in malformed documents
+        let open_kinds: Vec<InlineKind> =
+            self.inlines.iter().map(|(kind, _)| kind.clone()).collect();
+        while !self.inlines.is_empty() {
+            self.close_inline();
+        }
+        self.inlines = open_kinds
+            .into_iter()
+            .map(|kind| (kind, Vec::new()))
+            .collect();
+        let mut content = std::mem::take(&mut self.content);
+        trim_end(&mut content);
+        self.has_content = false;
+        self.pending = PendingWhitespace::None;
+        self.previous = None;
+        if !content.is_empty() {
+            let block = match self.text_block_kind() {
+                TextBlockKind::Heading(level) => Block::Heading { level, content },
+                _ => Block::Paragraph { content },
+            };
+            self.push_block(block);
+        }
+        let preformatted = std::mem::take(&mut self.preformatted);
+        // a newline directly after the opening tag is ignored
+        let preformatted = preformatted.strip_prefix('\n').unwrap_or(&preformatted);
+        if !preformatted.trim().is_empty() {
+            self.push_block(Block::Preformatted {
+                text: preformatted.trim_end().to_string(),
+            });
+        }
+    }
+
+    fn push_block(&mut self, block: Block) {
+        match self.containers.last_mut() {
+            Some(Container::Root(blocks))
+            | Some(Container::Quote(blocks))
+            | Some(Container::ListItem(blocks)) => blocks.push(block),
+            Some(Container::List { items, .. }) => items.push(vec![block]),
+            Some(Container::Table(rows)) => rows.push(vec![into_inline_content(block)]),
+            Some(Container::Row(cells)) => cells.push(into_inline_content(block)),
+            Some(Container::Cell(content)) => {
+                if !content.is_empty() {
+                    content.push(Inline::LineBreak);
+                }
+                content.append(&mut into_inline_content(block));
+            }
+            None => {}
+        }
+    }
+
+    fn close_container(&mut self) {
+        // the root container is never closed
+        if self.containers.len() <= 1 {
+            return;
+        }
+        let parent = self.containers.get(self.containers.len() - 2);
+        let parent_is_list = matches!(parent, Some(Container::List { .. }));
+        let parent_is_table = matches!(parent, Some(Container::Table(_)));
+        let parent_is_row = matches!(parent, Some(Container::Row(_)));
+        match self.containers.pop() {
+            Some(Container::ListItem(blocks)) if parent_is_list => {
+                if let Some(Container::List { items, .. }) = self.containers.last_mut() {
+                    items.push(blocks);
+                }
+            }
+            Some(Container::ListItem(blocks)) => {
+                blocks.into_iter().for_each(|block| self.push_block(block))
+            }
+            Some(Container::Row(cells)) if parent_is_table => {
+                if let Some(Container::Table(rows)) = self.containers.last_mut() {
+                    rows.push(cells);
+                }
+            }
+            Some(Container::Row(cells)) => self.push_block(Block::Table { rows: vec![cells] }),
+            Some(Container::Cell(content)) if parent_is_row => {
+                if let Some(Container::Row(cells)) = self.containers.last_mut() {
+                    cells.push(content);
+                }
+            }
+            Some(Container::Cell(content)) if !content.is_empty() => {
+                self.push_block(Block::Paragraph { content })
+            }
+            Some(Container::Quote(blocks)) if !blocks.is_empty() => {
+                self.push_block(Block::Quote { blocks })
+            }
+            Some(Container::List { ordered, items }) if !items.is_empty() => {
+                self.push_block(Block::List { ordered, items })
+            }
+            Some(Container::Table(rows)) if !rows.is_empty() => {
+                self.push_block(Block::Table { rows })
+            }
+            _ => {}
+        }
+    }
+}
+
+impl ContentHandler for BlockBuilder {
+    fn start_element(&mut self, element: &xmltree::Element) {
+        if self.skip_depth > 0 {
+            self.skip_depth += 1;
+            return;
+        }
+        let role = get_role(element);
+        match &role {
+            Role::Skip => self.skip_depth = 1,
+            Role::TextBlock(kind) => {
+                self.flush();
+                self.text_block_kinds.push(*kind);
+            }
+            Role::Container(kind) => {
+                self.flush();
+                self.containers.push(Container::new(*kind));
+            }
+            Role::Inline(kind) => self.open_inline(kind.clone()),
+            Role::LineBreak => self.line_break(),
+            Role::Boundary => self.flush(),
+            Role::Transparent => {}
+        }
+        self.open.push(role);
+    }
+
+    fn end_element(&mut self, element: &xmltree::Element) {
+        if self.skip_depth > 1 {
+            self.skip_depth -= 1;
+            return;
+        }
+        // elements that were opened before the start anchor are not on the stack
+        let role = match self.open.pop() {
+            Some(role) => role,
+            None => {
+                if !matches!(
+                    get_role(element),
+                    Role::Inline(_) | Role::LineBreak | Role::Transparent
+                ) {
+                    self.flush();
+                }
+                return;
+            }
+        };
+        match role {
+            Role::Skip => self.skip_depth = 0,
+            Role::TextBlock(_) => {
+                self.flush();
+                self.text_block_kinds.pop();
+            }
+            Role::Container(_) => {
+                self.flush();
+                self.close_container();
+            }
+            Role::Inline(_) => self.close_inline(),
+            Role::Boundary => self.flush(),
+            Role::LineBreak | Role::Transparent => {}
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        if self.skip_depth > 0 {
+            return;
+        }
+        if let TextBlockKind::Preformatted = self.text_block_kind() {
+            self.preformatted.push_str(text);
+            return;
+        }
+        for c in text.chars() {
+            if is_collapsible_whitespace(c) {
+                self.pending.add(c);
+            } else {
+                self.emit_pending_whitespace(Some(c));
+                let mut buf = [0; 4];
+                push_str(self.current_content(), c.encode_utf8(&mut buf));
+                self.has_content = true;
+                self.previous = Some(c);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::util::{count_anchors, parse_html, walk_html};
+
+    fn text(s: &str) -> Inline {
+        Inline::Text {
+            text: s.to_string(),
+        }
+    }
+
+    fn html_to_blocks(
+        html: &str,
+        start_anchor: Option<&str>,
+        stop_anchor: Option<&str>,
+    ) -> Vec<Block> {
+        let root = parse_html(html);
+        let mut anchor_counts = HashMap::new();
+        count_anchors(&root, &mut anchor_counts);
+        let mut block_builders = [BlockBuilder::new(false), BlockBuilder::new(false)];
+        walk_html(
+            &root,
+            &[start_anchor, stop_anchor],
+            &anchor_counts,
+            None,
+            &mut block_builders,
+        );
+        let [block_builder, _] = block_builders;
+        block_builder.finish()
+    }
+
+    #[test]
+    fn text_blocks_and_inlines() {
+        let html = r#"<html><head><title>Title</title></head><body>
+<h2 id="start">  The <em>first</em>
+   chapter </h2>
+<div><p>Some <b>bold</b>, <a href="notes.xhtml#n1">linked<sup>1</sup></a> text.<br/>
+  Next line<br/></p>
+text outside of paragraph</div>
+</body></html>"#;
+        let expected = vec![
+            Block::Heading {
+                level: 2,
+                content: vec![
+                    text("The "),
+                    Inline::Emphasis {
+                        content: vec![text("first")],
+                    },
+                    text(" chapter"),
+                ],
+            },
+            Block::Paragraph {
+                content: vec![
+                    text("Some "),
+                    Inline::Strong {
+                        content: vec![text("bold")],
+                    },
+                    text(", "),
+                    Inline::Link {
+                        href: "notes.xhtml#n1".to_string(),
+                        content: vec![text("linked1")],
+                    },
+                    text(" text."),
+                    Inline::LineBreak,
+                    text("Next line"),
+                ],
+            },
+            Block::Paragraph {
+                content: vec![text("text outside of paragraph")],
+            },
+        ];
+        assert_eq!(expected, html_to_blocks(html, None, None));
+    }
+
+    #[test]
+    fn container_blocks() {
+        let html = r#"<html><body>
+<blockquote><p>quoted</p><p>twice</p></blockquote>
+<ol><li>one</li><li><p>two</p><ul><li>nested</li></ul></li></ol>
+<pre>
+  let x = 1;
+    x + 1
+</pre>
+<table><thead><tr><th>A</th><th>B</th></tr></thead>
+<tbody><tr><td>1</td><td><p>2</p><p>3</p></td></tr></tbody></table>
+</body></html>"#;
+        let expected = vec![
+            Block::Quote {
+                blocks: vec![
+                    Block::Paragraph {
+                        content: vec![text("quoted")],
+                    },
+                    Block::Paragraph {
+                        content: vec![text("twice")],
+                    },
+                ],
+            },
+            Block::List {
+                ordered: true,
+                items: vec![
+                    vec![Block::Paragraph {
+                        content: vec![text("one")],
+                    }],
+                    vec![
+                        Block::Paragraph {
+                            content: vec![text("two")],
+                        },
+                        Block::List {
+                            ordered: false,
+                            items: vec![vec![Block::Paragraph {
+                                content: vec![text("nested")],
+                            }]],
+                        },
+                    ],
+                ],
+            },
+            Block::Preformatted {
+                text: "  let x = 1;\n    x + 1".to_string(),
+            },
+            Block::Table {
+                rows: vec![
+                    vec![vec![text("A")], vec![text("B")]],
+                    vec![
+                        vec![text("1")],
+                        vec![text("2"), Inline::LineBreak, text("3")],
+                    ],
+                ],
+            },
+        ];
+        assert_eq!(expected, html_to_blocks(html, None, None));
+    }
+
+    #[test]
+    fn blocks_as_text() {
+        let html = r#"<html><head><title>Title</title></head><body>
+<h1>Heading</h1>
+<p>First   <em>paragraph</em>,<br/>
+   second line.</p>
+<ul><li>item 1</li><li>item 2</li></ul>
+<pre>  indented
+    more</pre>
+<table><tr><td>a</td><td>b</td></tr><tr><td>c</td><td>d</td></tr></table>
+</body></html>"#;
+        let expected = "Heading\n\nFirst paragraph,\nsecond line.\n\nitem 1\n\nitem 2\n\n  indented\n    more\n\na\tb\nc\td";
+        assert_eq!(expected, blocks_to_text(&html_to_blocks(html, None, None)));
+    }
+
+    #[test]
+    fn blocks_between_anchors() {
+        let html = r#"<html><body>
+<blockquote><p>before</p><p id="start">first <em>quoted</em></p><p>second</p></blockquote>
+<p>after <span id="end">stop</span> here</p>
+</body></html>"#;
+        let expected = vec![
+            Block::Paragraph {
+                content: vec![
+                    text("first "),
+                    Inline::Emphasis {
+                        content: vec![text("quoted")],
+                    },
+                ],
+            },
+            Block::Paragraph {
+                content: vec![text("second")],
+            },
+            Block::Paragraph {
+                content: vec![text("after")],
+            },
+        ];
+        assert_eq!(expected, html_to_blocks(html, Some("start"), Some("end")));
+    }
+
+    #[test]
+    fn cjk_line_breaks() {
+        let html = "<html><body><p>我姐夫的\n<i>祖父</i>，\n名叫宋焘 and\nmore</p></body></html>";
+        let expected = vec![Block::Paragraph {
+            content: vec![
+                text("我姐夫的"),
+                Inline::Emphasis {
+                    content: vec![text("祖父")],
+                },
+                text("，名叫宋焘 and more"),
+            ],
+        }];
+        assert_eq!(expected, html_to_blocks(html, None, None));
+    }
+
+    #[test]
+    fn cjk_ruby_annotations() {
+        let html = "<html><body><p><ruby>漢<rp>(</rp><rt>かん</rt><rp>)</rp>字<rt>じ</rt></ruby>を読む</p></body></html>";
+        let expected = vec![Block::Paragraph {
+            content: vec![text("漢字を読む")],
+        }];
+        assert_eq!(expected, html_to_blocks(html, None, None));
+    }
+}
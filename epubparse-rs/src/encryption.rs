@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use xmltree::Element;
+
+use crate::parse::get_full_path;
+use crate::types::{EncryptionScheme, Metadata};
+
+pub const ENCRYPTION_PATH: &str = "META-INF/encryption.xml";
+pub const RIGHTS_PATH: &str = "META-INF/rights.xml";
+// license document of Readium LCP
+const LCP_LICENSE_PATH: &str = "META-INF/license.lcpl";
+// rights information of Apple FairPlay
+const SINF_PATH: &str = "META-INF/sinf.xml";
+
+const ADEPT_NAMESPACE: &str = "http://ns.adobe.com/adept";
+
+/// Font obfuscation algorithm of the IDPF
+pub const IDPF_OBFUSCATION: &str = "http://www.idpf.org/2008/embedding";
+/// Font obfuscation algorithm of Adobe
+pub const ADOBE_OBFUSCATION: &str = "http://ns.adobe.com/pdf/enc#RC";
+// number of obfuscated bytes at the start of a font
+const IDPF_OBFUSCATED_LENGTH: usize = 1040;
+const ADOBE_OBFUSCATED_LENGTH: usize = 1024;
+
+/// Encrypted resources of an epub file, as declared in META-INF/encryption.xml
+pub struct Encryption {
+    // encryption algorithm of each encrypted resource, by path within the archive
+    pub resources: HashMap<String, String>,
+    pub scheme: EncryptionScheme,
+}
+
+impl Encryption {
+    /// Parse encryption.xml, using the presence of other files of the archive
+    /// (rights.xml, license documents) to classify the encryption scheme
+    pub fn new(
+        encryption_xml: &Element,
+        rights_xml: Option<&Element>,
+        has_file: impl Fn(&str) -> bool,
+    ) -> Self {
+        let mut resources = HashMap::new();
+        let mut adept_key = false;
+        let mut lcp_key = false;
+        for data in encryption_xml
+            .children
+            .iter()
+            .filter_map(|node| node.as_element())
+            .filter(|el| el.name == "EncryptedData")
+        {
+            let algorithm = data
+                .get_child("EncryptionMethod")
+                .and_then(|method| method.attributes.get("Algorithm"))
+                .cloned()
+                .unwrap_or_default();
+            let uri = data
+                .get_child("CipherData")
+                .and_then(|cipher_data| cipher_data.get_child("CipherReference"))
+                .and_then(|reference| reference.attributes.get("URI"));
+            if let Some(key_info) = data.get_child("KeyInfo") {
+                adept_key |= has_namespace(key_info, ADEPT_NAMESPACE);
+                lcp_key |= key_info
+                    .get_child("RetrievalMethod")
+                    .and_then(|method| method.attributes.get("URI"))
+                    .map(|uri| uri.starts_with("license.lcpl"))
+                    == Some(true);
+            }
+            // URIs are relative to the root of the archive, but may be escaped
+            if let Some(uri) = uri {
+                resources.insert(get_full_path(Path::new(""), uri), algorithm);
+            }
+        }
+
+        let obfuscation_only = resources
+            .values()
+            .all(|algorithm| is_obfuscation(algorithm));
+        let scheme = if obfuscation_only {
+            EncryptionScheme::FontObfuscation
+        } else if lcp_key || has_file(LCP_LICENSE_PATH) {
+            EncryptionScheme::ReadiumLcp
+        } else if has_file(SINF_PATH) {
+            EncryptionScheme::AppleFairPlay
+        } else if adept_key
+            || rights_xml.map(|rights| has_namespace(rights, ADEPT_NAMESPACE)) == Some(true)
+        {
+            EncryptionScheme::AdobeAdept
+        } else {
+            EncryptionScheme::Unknown
+        };
+        Encryption { resources, scheme }
+    }
+
+    /// Paths of the resources that can not be read without a decryption key
+    pub fn drm_protected(&self) -> impl Iterator<Item = &str> {
+        self.resources
+            .iter()
+            .filter(|(_path, algorithm)| !is_obfuscation(algorithm))
+            .map(|(path, _algorithm)| path.as_str())
+    }
+
+    /// Reverse the font obfuscation of the resource at the given path,
+    /// the key is derived from the identifiers of the book
+    ///
+    /// Returns false if the resource is obfuscated, but no key could be derived
+    pub fn deobfuscate(&self, path: &str, data: &mut [u8], metadata: &Metadata) -> bool {
+        let (key, length) = match self.resources.get(path).map(String::as_str) {
+            Some(IDPF_OBFUSCATION) => (
+                metadata.unique_identifier.as_deref().map(idpf_key),
+                IDPF_OBFUSCATED_LENGTH,
+            ),
+            Some(ADOBE_OBFUSCATION) => (adobe_key(metadata), ADOBE_OBFUSCATED_LENGTH),
+            _ => return true,
+        };
+        let key = match key {
+            Some(key) => key,
+            None => return false,
+        };
+        data.iter_mut()
+            .take(length)
+            .zip(key.iter().cycle())
+            .for_each(|(byte, key_byte)| *byte ^= key_byte);
+        true
+    }
+}
+
+// SHA-1 digest of the unique identifier, without whitespace
+fn idpf_key(unique_identifier: &str) -> Vec<u8> {
+    let identifier: String = unique_identifier
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '\t' | '\r' | '\n'))
+        .collect();
+    sha1_smol::Sha1::from(identifier).digest().bytes().to_vec()
+}
+
+// the 16 bytes of the book's uuid, preferably given by the unique identifier
+fn adobe_key(metadata: &Metadata) -> Option<Vec<u8>> {
+    let to_key = |identifier: &str| {
+        let hex: String = identifier
+            .trim()
+            .trim_start_matches("urn:uuid:")
+            .chars()
+            .filter(|c| *c != '-')
+            .collect();
+        if hex.len() != 32 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+            .collect()
+    };
+    metadata
+        .unique_identifier
+        .iter()
+        .chain(
+            metadata
+                .identifiers
+                .iter()
+                .map(|identifier| &identifier.value),
+        )
+        .find_map(|identifier| to_key(identifier))
+}
+
+pub fn is_obfuscation(algorithm: &str) -> bool {
+    algorithm == IDPF_OBFUSCATION || algorithm == ADOBE_OBFUSCATION
+}
+
+fn has_namespace(element: &Element, namespace: &str) -> bool {
+    element.namespace.as_deref() == Some(namespace)
+        || element
+            .children
+            .iter()
+            .filter_map(|node| node.as_element())
+            .any(|child| has_namespace(child, namespace))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::get_parser_config;
+
+    fn parse(xml: &str) -> Element {
+        Element::parse_with_config(xml.as_bytes(), get_parser_config(xml)).unwrap()
+    }
+
+    fn encryption_xml(algorithm: &str, key_info: &str) -> Element {
+        parse(&format!(
+            r#"<encryption xmlns="urn:oasis:names:tc:opendocument:xmlns:container"
+    xmlns:enc="http://www.w3.org/2001/04/xmlenc#" xmlns:ds="http://www.w3.org/2000/09/xmldsig#">
+  <enc:EncryptedData>
+    <enc:EncryptionMethod Algorithm="{}"/>
+    {}
+    <enc:CipherData><enc:CipherReference URI="OEBPS/text/ch001.xhtml"/></enc:CipherData>
+  </enc:EncryptedData>
+</encryption>"#,
+            algorithm, key_info
+        ))
+    }
+
+    #[test]
+    fn font_obfuscation_is_not_drm() {
+        let xml = encryption_xml(IDPF_OBFUSCATION, "");
+        let encryption = Encryption::new(&xml, None, |_| false);
+        assert_eq!(EncryptionScheme::FontObfuscation, encryption.scheme);
+        assert_eq!(0, encryption.drm_protected().count());
+    }
+
+    #[test]
+    fn classify_drm_schemes() {
+        let aes = "http://www.w3.org/2001/04/xmlenc#aes128-cbc";
+        let adept_key = r#"<ds:KeyInfo><resource xmlns="http://ns.adobe.com/adept">urn:uuid:1</resource></ds:KeyInfo>"#;
+        let xml = encryption_xml(aes, adept_key);
+        let encryption = Encryption::new(&xml, None, |_| false);
+        assert_eq!(EncryptionScheme::AdobeAdept, encryption.scheme);
+        assert_eq!(
+            vec!["OEBPS/text/ch001.xhtml"],
+            encryption.drm_protected().collect::<Vec<_>>()
+        );
+
+        let xml = encryption_xml(aes, "");
+        let rights = parse(
+            r#"<adept:rights xmlns:adept="http://ns.adobe.com/adept"><adept:licenseToken/></adept:rights>"#,
+        );
+        let encryption = Encryption::new(&xml, Some(&rights), |_| false);
+        assert_eq!(EncryptionScheme::AdobeAdept, encryption.scheme);
+
+        let lcp_key = r#"<ds:KeyInfo><ds:RetrievalMethod URI="license.lcpl#/encryption/content_key"/></ds:KeyInfo>"#;
+        let xml = encryption_xml(aes, lcp_key);
+        let encryption = Encryption::new(&xml, None, |_| false);
+        assert_eq!(EncryptionScheme::ReadiumLcp, encryption.scheme);
+
+        let xml = encryption_xml(aes, "");
+        let encryption = Encryption::new(&xml, None, |path| path == SINF_PATH);
+        assert_eq!(EncryptionScheme::AppleFairPlay, encryption.scheme);
+
+        let encryption = Encryption::new(&xml, None, |_| false);
+        assert_eq!(EncryptionScheme::Unknown, encryption.scheme);
+    }
+
+    #[test]
+    fn escaped_resource_paths() {
+        let xml = parse(&format!(
+            r#"<encryption xmlns="urn:oasis:names:tc:opendocument:xmlns:container"
+    xmlns:enc="http://www.w3.org/2001/04/xmlenc#">
+  <enc:EncryptedData>
+    <enc:EncryptionMethod Algorithm="{}"/>
+    <enc:CipherData><enc:CipherReference URI="/OEBPS/text/../fonts/My%20Font.otf"/></enc:CipherData>
+  </enc:EncryptedData>
+</encryption>"#,
+            IDPF_OBFUSCATION
+        ));
+        let encryption = Encryption::new(&xml, None, |_| false);
+        assert_eq!(
+            vec!["OEBPS/fonts/My Font.otf"],
+            encryption.resources.keys().collect::<Vec<_>>()
+        );
+
+        let metadata = Metadata {
+            unique_identifier: Some("urn:uuid:1".to_string()),
+            ..Default::default()
+        };
+        let mut data = vec![0; 2048];
+        assert!(encryption.deobfuscate("OEBPS/fonts/My Font.otf", &mut data, &metadata));
+        assert_eq!(idpf_key("urn:uuid:1")[..], data[..20]);
+        assert!(data[IDPF_OBFUSCATED_LENGTH..].iter().all(|byte| *byte == 0));
+    }
+}
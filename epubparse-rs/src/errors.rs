@@ -1,32 +1,233 @@
-use std::{io, string};
+use std::{fmt, io, string};
 
+use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
 use thiserror::Error;
 
+use crate::types::EncryptionScheme;
+
 /// The top-level Error type that captures all failure scenarios
 /// of the epub -> book conversion
 #[derive(Error, Debug)]
 pub enum ParseError {
-    #[error("File error")]
+    #[error("File error: {0}")]
     FileError(#[from] io::Error),
-    #[error("Error in underlying Zip archive")]
-    ZipError(#[from] zip::result::ZipError),
-    #[error("Invalid UTF8")]
-    UTF8Error(#[from] string::FromUtf8Error),
+    /// The archive can not be opened, or a file of the archive (given by its path) can not be read
+    #[error("Error in underlying Zip archive{}: {source}", in_path(.path))]
+    ZipError {
+        path: Option<String>,
+        #[source]
+        source: zip::result::ZipError,
+    },
+    #[error("Invalid UTF8 in `{path}`")]
+    UTF8Error {
+        path: String,
+        #[source]
+        source: string::FromUtf8Error,
+    },
     #[error(transparent)]
     EpubError(#[from] MalformattedEpubError),
+    /// Content documents are encrypted and can not be read without a decryption key
+    #[error("Epub is DRM protected ({scheme}), encrypted: `{path}`")]
+    DrmProtected {
+        scheme: EncryptionScheme,
+        /// Path of an encrypted content document
+        path: String,
+    },
 }
 
-/// Failure scenarios for malformatted epub file that is a valid zip file
+fn in_path(path: &Option<String>) -> String {
+    path.as_ref()
+        .map(|path| format!(" at `{}`", path))
+        .unwrap_or_default()
+}
+
+// errors are serialized as their kind, message and the path within the archive (if any),
+// malformatted epub errors additionally with their context
+impl Serialize for ParseError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (kind, path) = match self {
+            ParseError::FileError(_) => ("FileError", None),
+            ParseError::ZipError { path, .. } => ("ZipError", path.as_deref()),
+            ParseError::UTF8Error { path, .. } => ("UTF8Error", Some(path.as_str())),
+            ParseError::EpubError(err) => ("EpubError", Some(err.context().path.as_str())),
+            ParseError::DrmProtected { path, .. } => ("DrmProtected", Some(path.as_str())),
+        };
+        let mut state = serializer.serialize_struct("ParseError", 4)?;
+        state.serialize_field("type", kind)?;
+        state.serialize_field("message", &self.to_string())?;
+        match path {
+            Some(path) => state.serialize_field("path", path)?,
+            None => state.skip_field("path")?,
+        }
+        match self {
+            ParseError::EpubError(err) => state.serialize_field("error", err)?,
+            ParseError::DrmProtected { scheme, .. } => state.serialize_field("scheme", scheme)?,
+            _ => state.skip_field("error")?,
+        }
+        state.end()
+    }
+}
+
+/// Failure scenarios of the book -> epub conversion
 #[derive(Error, Debug)]
+pub enum WriteError {
+    #[error("File error: {0}")]
+    FileError(#[from] io::Error),
+    #[error("Error in underlying Zip archive: {0}")]
+    ZipError(#[source] zip::result::ZipError),
+}
+
+// writing errors of the underlying file are reported as file errors
+impl From<zip::result::ZipError> for WriteError {
+    fn from(err: zip::result::ZipError) -> Self {
+        match err {
+            zip::result::ZipError::Io(err) => WriteError::FileError(err),
+            err => WriteError::ZipError(err),
+        }
+    }
+}
+
+// reading errors of the underlying file are reported as file errors
+impl From<zip::result::ZipError> for ParseError {
+    fn from(err: zip::result::ZipError) -> Self {
+        match err {
+            zip::result::ZipError::Io(err) => ParseError::FileError(err),
+            source => ParseError::ZipError { path: None, source },
+        }
+    }
+}
+
+/// Non-fatal problems of an epub file, that were recovered from
+/// or resulted in parts of the file being ignored
+#[derive(Error, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ParseWarning {
+    #[error("Manifest item `{id}` skipped, missing attribute `{attribute}`")]
+    SkippedManifestItem { id: String, attribute: String },
+    #[error("TOC target `{target}` is not in the spine")]
+    UnresolvedTocTarget { target: String },
+    #[error("No usable table of contents, derived chapters from the spine: {reason}")]
+    TocFromSpine { reason: String },
+    #[error("Navigation document `{path}` not usable, used toc.ncx instead: {reason}")]
+    UnusableNavDoc { path: String, reason: String },
+    #[error("Anchor `{anchor}` not found in `{path}`")]
+    MissingAnchor { path: String, anchor: String },
+    #[error("Duplicate id `{id}` in `{path}`")]
+    DuplicateId { path: String, id: String },
+    #[error("Malformed html in `{path}` parsed leniently, err: `{error}`")]
+    RecoveredHtml { path: String, error: String },
+    #[error("Font `{path}` is obfuscated, but no key could be derived from the identifiers")]
+    ObfuscatedResource { path: String },
+    #[error("Resource `{path}` is DRM protected ({scheme}) and can not be read")]
+    EncryptedResource {
+        path: String,
+        scheme: EncryptionScheme,
+    },
+}
+
+impl ParseWarning {
+    /// Path of the file inside the archive the warning refers to, if any
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            ParseWarning::MissingAnchor { path, .. }
+            | ParseWarning::DuplicateId { path, .. }
+            | ParseWarning::RecoveredHtml { path, .. }
+            | ParseWarning::UnusableNavDoc { path, .. }
+            | ParseWarning::ObfuscatedResource { path }
+            | ParseWarning::EncryptedResource { path, .. } => Some(path),
+            ParseWarning::SkippedManifestItem { .. }
+            | ParseWarning::UnresolvedTocTarget { .. }
+            | ParseWarning::TocFromSpine { .. } => None,
+        }
+    }
+}
+
+/// Failure scenarios for malformatted epub file that is a valid zip file
+#[derive(Error, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type")]
 pub enum MalformattedEpubError {
-    #[error("Malformatted/missing container.xml file")]
-    MalformattedContainer,
-    #[error("Malformatted content.opf file")]
-    MalformattedContentOpf,
-    #[error("Malformatted toc.ncx file, err: `{0}`")]
-    MalformattedTocNcx(String),
-    #[error("Malformatted manifest or missing resources")]
-    MalformattedManifest,
-    #[error("Could not process HTML resource, file: `{0}`, error: `{1}`")]
-    MalformattedHTML(String, xmltree::ParseError),
+    #[error("Malformatted/missing container.xml file: {0}")]
+    MalformattedContainer(Box<ErrorContext>),
+    #[error("Malformatted content.opf file: {0}")]
+    MalformattedContentOpf(Box<ErrorContext>),
+    #[error("Malformatted toc.ncx file: {0}")]
+    MalformattedTocNcx(Box<ErrorContext>),
+    #[error("Malformatted navigation document: {0}")]
+    MalformattedNavDoc(Box<ErrorContext>),
+    #[error("Malformatted manifest or missing resources: {0}")]
+    MalformattedManifest(Box<ErrorContext>),
+    #[error("Malformatted encryption.xml file: {0}")]
+    MalformattedEncryption(Box<ErrorContext>),
+}
+
+impl MalformattedEpubError {
+    pub fn context(&self) -> &ErrorContext {
+        match self {
+            MalformattedEpubError::MalformattedContainer(context)
+            | MalformattedEpubError::MalformattedContentOpf(context)
+            | MalformattedEpubError::MalformattedTocNcx(context)
+            | MalformattedEpubError::MalformattedNavDoc(context)
+            | MalformattedEpubError::MalformattedManifest(context)
+            | MalformattedEpubError::MalformattedEncryption(context) => context,
+        }
+    }
+}
+
+/// Where in the epub file a failure occurred and what was wrong
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorContext {
+    /// Path of the file inside the archive
+    pub path: String,
+    pub reason: String,
+    /// Element that is missing or invalid
+    pub element: Option<String>,
+    /// Attribute that is missing or invalid
+    pub attribute: Option<String>,
+    /// Line of the failure (1-based), only known for invalid XML
+    pub line: Option<u64>,
+    /// Column of the failure (1-based), only known for invalid XML
+    pub column: Option<u64>,
+}
+
+impl ErrorContext {
+    pub fn new(path: &str, reason: impl Into<String>) -> Self {
+        ErrorContext {
+            path: path.to_string(),
+            reason: reason.into(),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn element(mut self, element: &str) -> Self {
+        self.element = Some(element.to_string());
+        self
+    }
+
+    pub(crate) fn attribute(mut self, attribute: &str) -> Self {
+        self.attribute = Some(attribute.to_string());
+        self
+    }
+
+    // position of an xml-rs parse error, which is 0-based
+    pub(crate) fn position(mut self, position: xml::common::TextPosition) -> Self {
+        self.line = Some(position.row + 1);
+        self.column = Some(position.column + 1);
+        self
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}`: {}", self.path, self.reason)?;
+        if let Some(element) = &self.element {
+            write!(f, ", element `{}`", element)?;
+        }
+        if let Some(attribute) = &self.attribute {
+            write!(f, ", attribute `{}`", attribute)?;
+        }
+        if let (Some(line), Some(column)) = (self.line, self.column) {
+            write!(f, " (line {}, column {})", line, column)?;
+        }
+        Ok(())
+    }
 }
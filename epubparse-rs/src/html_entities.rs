@@ -1 +1,18 @@
+use std::collections::HashMap;
+
+use once_cell::sync::Lazy;
+
 include!(concat!(env!("OUT_DIR"), "/entities.rs"));
+
+// built once, the parser configuration needs an owned copy for every document using them
+static NAMED_ENTITIES_MAP: Lazy<HashMap<String, String>> = Lazy::new(|| {
+    NAMED_ENTITIES
+        .iter()
+        .map(|(name, chars)| (name.to_string(), chars.to_string()))
+        .collect()
+});
+
+/// Named html entities (without `&` and `;`) with their characters
+pub fn get_named_entities() -> &'static HashMap<String, String> {
+    &NAMED_ENTITIES_MAP
+}
@@ -4,18 +4,30 @@
 //! - ✅ serve as core to the epubparse-wasm library (must compile to WASM)
 //! - ✅ perform a reasonable conversion into a book with chapters
 //! - ✅ support Epub version 2 table of contents (.ncx)
-//! - ❌ support Epub version 3 table of contents (.xhtml) (not yet implemented, but
-//!   many version 3 epubs also include version 2 table of contents, these should also work)
+//! - ✅ support Epub version 3 table of contents (.xhtml) (preferred over the version 2
+//!   table of contents when both are present)
+//! - ✅ read content documents on demand ([`EpubArchive`]), so that listing metadata
+//!   or previewing the first chapter does not extract the whole book
+//! - ✅ write books back to Epub 3 files ([`book_to_epub`])
 
-use errors::ParseError;
-use parse::EpubArchive;
-use types::Book;
+use std::io::{Read, Seek};
+use std::path::Path;
 
+use errors::{ParseError, ParseWarning};
+use types::{Book, Finding, ParseOptions, Severity};
+
+pub use parse::{Chapters, EpubArchive, ManifestItem};
+pub use write::{book_to_epub, write_epub};
+
+mod blocks;
+mod encryption;
 pub mod errors;
 mod html_entities;
+mod notes;
 mod parse;
 pub mod types;
 mod util;
+mod write;
 
 /// Parse an epub file to a text-only book structure
 ///
@@ -24,15 +36,150 @@ pub fn epub_to_book(bytes: &[u8]) -> Result<Book, ParseError> {
     EpubArchive::new(bytes).and_then(|archive| archive.to_book())
 }
 
+/// Parse an epub file to a book structure, as configured by the given options
+pub fn epub_to_book_with_options(bytes: &[u8], options: &ParseOptions) -> Result<Book, ParseError> {
+    EpubArchive::new(bytes).and_then(|archive| archive.to_book_with_options(options))
+}
+
+/// Parse an epub file to a book structure, as configured by the given options,
+/// also returning the non-fatal problems that were found
+///
+/// Problems that make the conversion impossible are returned as error
+pub fn epub_to_book_with_warnings(
+    bytes: &[u8],
+    options: &ParseOptions,
+) -> Result<(Book, Vec<ParseWarning>), ParseError> {
+    let archive = EpubArchive::new(bytes)?;
+    let book = archive.to_book_with_options(options)?;
+    Ok((book, archive.warnings()))
+}
+
+/// Parse the epub file at the given path to a book structure, as configured by the given options
+///
+/// Only the needed parts of the file are read into memory
+pub fn epub_file_to_book<P: AsRef<Path>>(
+    path: P,
+    options: &ParseOptions,
+) -> Result<Book, ParseError> {
+    EpubArchive::open(path).and_then(|archive| archive.to_book_with_options(options))
+}
+
+/// Parse an epub file from the given reader to a book structure, as configured by the given options
+pub fn epub_reader_to_book<R: Read + Seek>(
+    reader: R,
+    options: &ParseOptions,
+) -> Result<Book, ParseError> {
+    EpubArchive::from_reader(reader).and_then(|archive| archive.to_book_with_options(options))
+}
+
+/// Check an epub file against the core rules of the epub specification
+///
+/// Files that can not be opened at all result in a single error finding
+pub fn validate(bytes: &[u8]) -> Vec<Finding> {
+    match EpubArchive::new(bytes) {
+        Ok(archive) => archive.validate(),
+        Err(err) => {
+            let path = match &err {
+                ParseError::EpubError(err) => Some(err.context().path.clone()),
+                ParseError::DrmProtected { path, .. } => Some(path.clone()),
+                _ => None,
+            };
+            vec![Finding {
+                severity: Severity::Error,
+                path,
+                message: err.to_string(),
+            }]
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     static EPUB_PAID_OFF: &[u8] = include_bytes!("../../test_resources/paid_off.epub");
 
+    #[test]
+    fn validate_unreadable_epub() {
+        let findings = validate(b"not a zip file");
+        assert_eq!(1, findings.len());
+        assert_eq!(Severity::Error, findings[0].severity);
+    }
+
     #[test]
     fn epub_to_book_paid_off() {
         let book = epub_to_book(EPUB_PAID_OFF).unwrap();
         assert_eq!("Paid Off", &book.title);
+        assert!(book.chapters.iter().all(|ch| ch.blocks.is_none()));
+    }
+
+    #[test]
+    fn epub_file_to_book_paid_off() {
+        let options = ParseOptions::default();
+        let expected_book = epub_to_book(EPUB_PAID_OFF).unwrap();
+        let book = epub_file_to_book("../test_resources/paid_off.epub", &options).unwrap();
+        assert_eq!(expected_book, book);
+        let file = std::fs::File::open("../test_resources/paid_off.epub").unwrap();
+        let book = epub_reader_to_book(file, &options).unwrap();
+        assert_eq!(expected_book, book);
+    }
+
+    #[test]
+    fn epub_file_to_book_missing_file() {
+        let result = epub_file_to_book("../test_resources/missing.epub", &ParseOptions::default());
+        assert!(matches!(result, Err(ParseError::FileError(_))));
+    }
+
+    #[test]
+    fn epub_to_book_with_warnings_recovered() {
+        let options = ParseOptions::default();
+        let (_book, warnings) = epub_to_book_with_warnings(EPUB_PAID_OFF, &options).unwrap();
+        assert_eq!(Vec::<ParseWarning>::new(), warnings);
+
+        let bytes = include_bytes!("../../test_resources/simple-invalid_html.epub");
+        let (book, warnings) = epub_to_book_with_warnings(bytes, &options).unwrap();
+        assert!(!book.chapters.is_empty());
+        assert!(matches!(
+            warnings.as_slice(),
+            [ParseWarning::RecoveredHtml { path, .. }] if path == "EPUB/text/ch001.xhtml"
+        ));
+
+        let bytes = include_bytes!("../../test_resources/nested_no_toc.epub");
+        let (_book, warnings) = epub_to_book_with_warnings(bytes, &options).unwrap();
+        assert!(matches!(
+            warnings.as_slice(),
+            [ParseWarning::TocFromSpine { .. }]
+        ));
+    }
+
+    #[test]
+    fn epub_to_book_structured_content() {
+        let options = ParseOptions {
+            structured_content: true,
+            ..Default::default()
+        };
+        let book = epub_to_book_with_options(EPUB_PAID_OFF, &options).unwrap();
+        assert!(book.preface_blocks.is_some());
+        let blocks = book.chapters[0].blocks.as_ref().unwrap();
+        assert!(blocks
+            .iter()
+            .any(|block| matches!(block, types::Block::Heading { .. })));
+        assert!(blocks
+            .iter()
+            .any(|block| matches!(block, types::Block::Paragraph { .. })));
+    }
+
+    #[test]
+    fn epub_to_book_paragraphs() {
+        let options = ParseOptions {
+            text_format: types::TextFormat::Paragraphs,
+            ..Default::default()
+        };
+        let book = epub_to_book_with_options(EPUB_PAID_OFF, &options).unwrap();
+        let chapter = &book.chapters[1];
+        assert!(chapter.blocks.is_none());
+        let paragraphs: Vec<&str> = chapter.text.split("\n\n").collect();
+        assert!(paragraphs.len() > 10);
+        assert!(paragraphs.iter().all(|p| !p.trim().is_empty()));
     }
 }
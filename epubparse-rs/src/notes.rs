@@ -0,0 +1,387 @@
+use std::collections::{HashMap, HashSet};
+
+use xmltree::{Element, XMLNode};
+
+use crate::types::{Block, Inline, Note};
+use crate::util::get_all_text;
+
+/// Placeholder for a note marker in the extracted text, until the note positions are resolved
+/// (a noncharacter, which does not occur in the text of content documents)
+pub const NOTE_MARKER: char = '\u{FDD0}';
+
+// epub:type values of note bodies
+const NOTE_TYPES: [&str; 4] = ["footnote", "endnote", "rearnote", "note"];
+// elements that may be the body of a note that is only detected by its marker
+const NOTE_BODY_ELEMENTS: [&str; 7] = ["aside", "li", "p", "div", "span", "dd", "dt"];
+
+/// The role of an html element in the notes of a document
+pub enum NoteElement {
+    /// A marker referencing a note, e.g. `<a href="#fn1">1</a>`
+    Marker(Note),
+    /// The body of a note, with the note itself if it is not referenced by any marker
+    Body(Option<Note>),
+}
+
+/// Footnotes and endnotes of an html document, detected by their epub:type
+/// or as targets of footnote markers (e.g. `<sup><a href="#fn1">1</a></sup>`)
+///
+/// Only notes within the same document are detected
+pub struct NoteIndex {
+    // text of the note bodies by id
+    bodies: HashMap<String, String>,
+    // ids of the note bodies that are referenced by a marker
+    referenced: HashSet<String>,
+}
+
+impl NoteIndex {
+    pub fn new(root: &Element) -> Self {
+        let mut elements_by_id = HashMap::new();
+        collect_ids(root, &mut elements_by_id);
+        let mut markers = Vec::new();
+        find_markers(root, false, &elements_by_id, &mut markers);
+
+        let marker_ids: HashSet<&str> = markers
+            .iter()
+            .filter_map(|(marker, _target)| marker.attributes.get("id"))
+            .map(String::as_str)
+            .collect();
+        let referenced: HashSet<String> = markers
+            .iter()
+            .map(|(_marker, target)| target.to_string())
+            .collect();
+        let bodies = elements_by_id
+            .iter()
+            .filter(|(id, element)| is_typed_body(element) || referenced.contains(**id))
+            .map(|(id, element)| {
+                // back-links from the note to its marker are not part of the note text
+                let mut element = (*element).clone();
+                remove_back_links(&mut element, &marker_ids);
+                (id.to_string(), get_all_text(&element))
+            })
+            .collect();
+        NoteIndex { bodies, referenced }
+    }
+
+    pub fn get_note_element(&self, element: &Element) -> Option<NoteElement> {
+        let id = element.attributes.get("id");
+        if let Some(text) = id.and_then(|id| self.bodies.get(id)) {
+            let id = id.unwrap();
+            let note = if self.referenced.contains(id) {
+                None
+            } else {
+                Some(unreferenced_note(id, text.clone()))
+            };
+            return Some(NoteElement::Body(note));
+        }
+        if is_typed_body(element) {
+            return Some(NoteElement::Body(Some(unreferenced_note(
+                "",
+                get_all_text(element),
+            ))));
+        }
+        let target = get_link_target(element)?;
+        let text = self.bodies.get(target)?;
+        Some(NoteElement::Marker(Note {
+            id: target.to_string(),
+            marker_id: id.cloned(),
+            marker: get_all_text(element),
+            position: 0,
+            text: text.clone(),
+        }))
+    }
+}
+
+fn unreferenced_note(id: &str, text: String) -> Note {
+    Note {
+        id: id.to_string(),
+        marker_id: None,
+        marker: String::new(),
+        position: 0,
+        text,
+    }
+}
+
+fn has_epub_type(element: &Element, types: &[&str]) -> bool {
+    // epub:type is stored by its local name
+    element
+        .attributes
+        .get("type")
+        .map(|t| t.split_whitespace().any(|t| types.contains(&t)))
+        == Some(true)
+}
+
+fn is_typed_body(element: &Element) -> bool {
+    has_epub_type(element, &NOTE_TYPES)
+}
+
+// id of the element within the same document that a link points to
+fn get_link_target(element: &Element) -> Option<&str> {
+    if element.name != "a" {
+        return None;
+    }
+    element
+        .attributes
+        .get("href")?
+        .strip_prefix('#')
+        .filter(|target| !target.is_empty())
+}
+
+fn collect_ids<'e>(element: &'e Element, elements_by_id: &mut HashMap<&'e str, &'e Element>) {
+    if let Some(id) = element.attributes.get("id") {
+        elements_by_id.entry(id).or_insert(element);
+    }
+    for child in element.children.iter().filter_map(XMLNode::as_element) {
+        collect_ids(child, elements_by_id);
+    }
+}
+
+// marker texts are short, e.g. "1", "[12]", "*" or "iv"
+fn is_marker_text(text: &str) -> bool {
+    let text = text.trim_matches(|c: char| matches!(c, '[' | ']' | '(' | ')'));
+    !text.is_empty()
+        && text.chars().count() <= 3
+        && text
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '*' | '†' | '‡' | '§' | '¶'))
+}
+
+fn has_marker_class(element: &Element) -> bool {
+    element.attributes.get("class").map(|class| {
+        let class = class.to_lowercase();
+        ["footnote", "noteref", "fnref", "endnote"]
+            .iter()
+            .any(|hint| class.contains(hint))
+    }) == Some(true)
+}
+
+// find links that are note markers, with the ids of the notes they point to
+fn find_markers<'e>(
+    element: &'e Element,
+    in_sup: bool,
+    elements_by_id: &HashMap<&str, &Element>,
+    markers: &mut Vec<(&'e Element, &'e str)>,
+) {
+    for child in element.children.iter().filter_map(XMLNode::as_element) {
+        if let Some(target) = get_link_target(child) {
+            let target_element = elements_by_id.get(target);
+            let is_marker = has_epub_type(child, &["noteref"])
+                || target_element.map(|el| is_typed_body(el)) == Some(true)
+                || (is_marker_text(&get_all_text(child))
+                    && (in_sup || child.get_child("sup").is_some() || has_marker_class(child))
+                    && target_element.map(|el| NOTE_BODY_ELEMENTS.contains(&el.name.as_str()))
+                        == Some(true));
+            if is_marker {
+                markers.push((child, target));
+                continue;
+            }
+        }
+        find_markers(
+            child,
+            in_sup || child.name == "sup",
+            elements_by_id,
+            markers,
+        );
+    }
+}
+
+fn remove_back_links(element: &mut Element, marker_ids: &HashSet<&str>) {
+    element.children.retain(|child| match child {
+        XMLNode::Element(el) => {
+            get_link_target(el).map(|target| marker_ids.contains(target)) != Some(true)
+        }
+        _ => true,
+    });
+    for child in element.children.iter_mut() {
+        if let XMLNode::Element(el) = child {
+            remove_back_links(el, marker_ids);
+        }
+    }
+}
+
+/// Remove the note markers from the text, setting the positions (in characters) of the notes
+///
+/// The notes must be given in the order of their markers
+pub fn resolve_note_positions(text: &str, notes: &mut [Note]) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut position = 0;
+    let mut notes_iter = notes.iter_mut();
+    let mut after_marker = false;
+    for c in text.chars() {
+        if c == NOTE_MARKER {
+            if let Some(note) = notes_iter.next() {
+                note.position = position;
+            }
+            after_marker = true;
+            continue;
+        }
+        // the marker may have been surrounded by spaces
+        if after_marker && c == ' ' && (result.is_empty() || result.ends_with([' ', '\n'])) {
+            continue;
+        }
+        after_marker = false;
+        result.push(c);
+        position += 1;
+    }
+    let trimmed_len = result.trim_end().len();
+    result.truncate(trimmed_len);
+    let length = result.chars().count();
+    for note in notes.iter_mut() {
+        note.position = note.position.min(length);
+    }
+    result
+}
+
+/// Remove the note markers from the structured content
+pub fn strip_note_markers(blocks: &mut Vec<Block>) {
+    for block in blocks.iter_mut() {
+        match block {
+            Block::Paragraph { content } | Block::Heading { content, .. } => {
+                strip_inline_markers(content)
+            }
+            Block::List { items, .. } => items.iter_mut().for_each(strip_note_markers),
+            Block::Quote { blocks } => strip_note_markers(blocks),
+            Block::Table { rows } => rows
+                .iter_mut()
+                .flat_map(|row| row.iter_mut())
+                .for_each(strip_inline_markers),
+            Block::Preformatted { .. } => {}
+        }
+    }
+    // blocks that only contained a note
+    blocks.retain(|block| match block {
+        Block::Paragraph { content } => !content.is_empty(),
+        _ => true,
+    });
+}
+
+fn strip_inline_markers(content: &mut Vec<Inline>) {
+    for inline in content.iter_mut() {
+        match inline {
+            Inline::Text { text } if text.contains(NOTE_MARKER) => {
+                *text = text
+                    .replace(&format!(" {} ", NOTE_MARKER), " ")
+                    .replace(NOTE_MARKER, "");
+            }
+            Inline::Emphasis { content }
+            | Inline::Strong { content }
+            | Inline::Link { content, .. } => strip_inline_markers(content),
+            _ => {}
+        }
+    }
+    content.retain(|inline| match inline {
+        Inline::Text { text } => !text.is_empty(),
+        Inline::Emphasis { content } | Inline::Strong { content } => !content.is_empty(),
+        _ => true,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{ParseOptions, TextFormat};
+    use crate::util::{html_to_contents, HtmlContent};
+
+    fn html_to_content(html: &str, options: &ParseOptions) -> HtmlContent {
+        html_to_contents(html, &[None], None, options).swap_remove(0)
+    }
+
+    fn options() -> ParseOptions {
+        ParseOptions {
+            extract_notes: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn epub_type_footnotes() {
+        let html = r##"<html><body>
+<p>Text<a href="#fn1" class="footnote-ref" id="fnref1" epub:type="noteref"><sup>1</sup></a> continues.</p>
+<aside epub:type="footnote" id="fn1"><p>The note.<a href="#fnref1" class="footnote-back">↩︎</a></p></aside>
+<p>More text.</p>
+<aside epub:type="footnote"><p>Unreferenced note.</p></aside>
+</body></html>"##;
+        let content = html_to_content(html, &options());
+        assert_eq!("Text continues. More text.", content.text);
+        assert_eq!(
+            vec![
+                Note {
+                    id: "fn1".to_string(),
+                    marker_id: Some("fnref1".to_string()),
+                    marker: "1".to_string(),
+                    position: 4,
+                    text: "The note.".to_string(),
+                },
+                Note {
+                    id: "".to_string(),
+                    marker_id: None,
+                    marker: "".to_string(),
+                    position: 26,
+                    text: "Unreferenced note.".to_string(),
+                },
+            ],
+            content.notes
+        );
+        // notes are part of the text unless requested
+        let content = html_to_content(html, &ParseOptions::default());
+        assert!(content.text.contains("The note."));
+        assert!(content.notes.is_empty());
+    }
+
+    #[test]
+    fn linked_endnotes() {
+        let html = r##"<html><body>
+<p>Word <sup><a href="#n1" id="r1">[1]</a></sup> next, <a href="#sec">see 2</a>.</p>
+<h2 id="sec">Notes</h2>
+<ol><li id="n1"><a href="#r1">1.</a> Endnote text</li></ol>
+</body></html>"##;
+        let options = ParseOptions {
+            text_format: TextFormat::Paragraphs,
+            structured_content: true,
+            ..options()
+        };
+        let content = html_to_content(html, &options);
+        assert_eq!("Word next, see 2.\n\nNotes", content.text);
+        assert_eq!(1, content.notes.len());
+        assert_eq!("[1]", content.notes[0].marker);
+        assert_eq!("Endnote text", content.notes[0].text);
+        assert_eq!(5, content.notes[0].position);
+        let blocks = content.blocks.unwrap();
+        // the list only contained the note
+        assert_eq!(2, blocks.len());
+        assert!(!crate::blocks::blocks_to_text(&blocks).contains(NOTE_MARKER));
+    }
+
+    #[test]
+    fn note_positions_after_join() {
+        let html = r##"<html><body><p>First<a href="#fn" epub:type="noteref">*</a></p>
+<aside id="fn" epub:type="footnote">Note</aside></body></html>"##;
+        let contents = vec![
+            html_to_content(html, &options()),
+            html_to_content(html, &options()),
+        ];
+        let joined = HtmlContent::join(contents, &options());
+        assert_eq!("First\nFirst", joined.text);
+        let positions: Vec<usize> = joined.notes.iter().map(|note| note.position).collect();
+        assert_eq!(vec![5, 11], positions);
+    }
+
+    #[test]
+    fn notes_of_empty_parts_after_join() {
+        let text_html = "<html><body><p>First</p></body></html>";
+        let notes_html =
+            r#"<html><body><aside epub:type="endnote"><p>Endnote</p></aside></body></html>"#;
+        for text_format in [TextFormat::Flat, TextFormat::Paragraphs] {
+            let options = ParseOptions {
+                text_format,
+                ..options()
+            };
+            let contents = vec![
+                html_to_content(text_html, &options),
+                html_to_content(notes_html, &options),
+            ];
+            let joined = HtmlContent::join(contents, &options);
+            assert_eq!(1, joined.notes.len());
+            assert_eq!("Endnote", joined.notes[0].text);
+        }
+    }
+}
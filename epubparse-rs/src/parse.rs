@@ -1,22 +1,39 @@
-use std::io::Read;
-use std::{collections::HashMap, io, path::PathBuf};
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Seek};
+use std::path::Path;
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
 
-use io::Cursor;
-use regex::Regex;
+use xml::common::Position;
 use xmltree::Element;
 use zip::{result::ZipError, ZipArchive};
 
+use crate::encryption::{Encryption, ENCRYPTION_PATH, RIGHTS_PATH};
 use crate::util::get_parser_config;
 use crate::{
-    errors::{MalformattedEpubError, ParseError},
-    types::{Book, Chapter},
-    util,
+    errors::{ErrorContext, MalformattedEpubError, ParseError, ParseWarning},
+    types::{
+        Book, Chapter, Contributor, Date, Identifier, Metadata, ParseOptions, Resource, Rootfile,
+        TocEntry,
+    },
+    util::{self, HtmlContent},
 };
 
-struct ZipArchiveWrapper<'a> {
-    zip_archive: ZipArchive<Cursor<&'a [u8]>>,
+mod validate;
+
+const NCX_MEDIA_TYPE: &str = "application/x-dtbncx+xml";
+const CONTAINER_PATH: &str = "META-INF/container.xml";
+const PACKAGE_MEDIA_TYPE: &str = "application/oebps-package+xml";
+
+struct ZipArchiveWrapper<R: Read + Seek> {
+    zip_archive: ZipArchive<R>,
 }
 
+/// A resource declared in the manifest of the package document
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ManifestItem {
     id: String,
     href: String,
@@ -24,16 +41,113 @@ pub struct ManifestItem {
     properties: Option<String>,
 }
 
+impl ManifestItem {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Location of the resource, relative to the package document
+    pub fn href(&self) -> &str {
+        &self.href
+    }
+
+    pub fn media_type(&self) -> &str {
+        &self.media_type
+    }
+
+    /// Epub 3 properties, e.g. "nav" or "cover-image"
+    pub fn properties(&self) -> impl Iterator<Item = &str> {
+        self.properties
+            .iter()
+            .flat_map(|props| props.split_whitespace())
+    }
+}
+
 pub type ItemId = String;
 pub type Manifest = HashMap<ItemId, ManifestItem>;
 pub type Spine = Vec<ItemId>;
 
 pub struct ContentOPF {
-    pub title: String,
-    pub author: Option<String>,
-    pub language: String,
+    // path of the package document in the archive
+    pub path: String,
+    // has at least one title and language
+    pub metadata: Metadata,
     pub manifest: Manifest,
     pub spine: Spine,
+    // manifest id of the toc.ncx, given by the toc attribute of the spine
+    pub toc_id: Option<String>,
+    // manifest id given by the Epub 2 <meta name="cover"> element
+    pub cover_id: Option<String>,
+    // href of the cover reference in the Epub 2 guide
+    pub guide_cover_href: Option<String>,
+    pub warnings: Vec<ParseWarning>,
+}
+
+impl ContentOPF {
+    /// the main title of the book
+    pub fn title(&self) -> &str {
+        &self.metadata.titles[0]
+    }
+
+    /// prefer creators explicitly marked as author over e.g. a translator listed first
+    pub fn author(&self) -> Option<&str> {
+        self.metadata
+            .creators
+            .iter()
+            .find(|creator| creator.has_role("aut"))
+            .or_else(|| self.metadata.creators.first())
+            .map(|creator| creator.name.as_str())
+    }
+
+    /// the primary language of the book
+    pub fn language(&self) -> &str {
+        &self.metadata.languages[0]
+    }
+
+    /// get the Epub 3 navigation document, which is flagged by the "nav" property
+    pub fn get_nav_item(&self) -> Option<&ManifestItem> {
+        self.manifest
+            .values()
+            .find(|item| item.properties().any(|prop| prop == "nav"))
+    }
+
+    /// get the Epub 2 toc.ncx, which is referenced by the toc attribute of the spine,
+    /// or else found by its media type
+    pub fn get_ncx_item(&self) -> Option<&ManifestItem> {
+        self.toc_id
+            .as_ref()
+            .and_then(|toc_id| self.manifest.get(toc_id))
+            .or_else(|| {
+                let mut ncx_items: Vec<&ManifestItem> = self
+                    .manifest
+                    .values()
+                    .filter(|item| item.media_type == NCX_MEDIA_TYPE)
+                    .collect();
+                // the manifest is unordered
+                ncx_items.sort_by(|a, b| a.href.cmp(&b.href));
+                ncx_items.first().copied()
+            })
+            .or_else(|| self.manifest.get("ncx"))
+    }
+
+    /// get the cover image, which is flagged by the "cover-image" property (Epub 3)
+    /// or referenced by a <meta name="cover"> element (Epub 2)
+    pub fn get_cover_image_item(&self) -> Option<&ManifestItem> {
+        let cover_image_item = self
+            .manifest
+            .values()
+            .find(|item| item.properties().any(|prop| prop == "cover-image"));
+        // the meta element sometimes refers to the href instead of the id
+        let cover_meta_item = || {
+            let cover_id = self.cover_id.as_ref()?;
+            self.manifest
+                .get(cover_id)
+                .or_else(|| self.manifest.values().find(|item| &item.href == cover_id))
+        };
+        cover_image_item
+            .or_else(cover_meta_item)
+            .filter(|item| item.media_type.starts_with("image/"))
+    }
 }
 
 #[derive(PartialEq, Eq, Hash)]
@@ -47,6 +161,8 @@ pub struct NavPoint {
 }
 
 pub struct TocNcx {
+    // path of the navigation document in the archive
+    pub path: String,
     // maximum of 4 is allowed
     pub depth: usize,
     // ordered list of top-level nav points
@@ -70,163 +186,487 @@ impl TocNcx {
     }
 }
 
-pub struct EpubArchive<'a> {
-    zip: ZipArchiveWrapper<'a>,
-    manifest_html_files: HashMap<String, String>,
-    pub content_opf_dir: PathBuf,
-    pub content_opf: ContentOPF,
-    pub navigation: TocNcx,
+/// An opened epub file
+///
+/// Opening only parses the container, the package document and the table of contents,
+/// the content documents are read from the archive when their content is requested
+pub struct EpubArchive<R: Read + Seek> {
+    // reading from the zip archive requires mutable access
+    zip: RefCell<ZipArchiveWrapper<R>>,
+    // renditions listed in container.xml
+    rootfiles: Vec<Rootfile>,
+    content_opf_dir: PathBuf,
+    content_opf: ContentOPF,
+    navigation: TocNcx,
+    // obfuscated fonts, declared in encryption.xml
+    encryption: Option<Encryption>,
+    // problems found so far, while opening the archive and extracting content
+    warnings: RefCell<Vec<ParseWarning>>,
+    // parts of parsed content documents that are still needed
+    parts: RefCell<PartCache>,
+}
+
+// a content document, or the part of it starting at an anchor
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Source {
+    // normalized path within the archive
+    path: String,
+    anchor: Option<String>,
+}
+
+// where the content of the preface and of each nav point is found
+struct ContentLayout<'n> {
+    preface: Vec<Source>,
+    nav_points: HashMap<&'n NavPoint, Vec<Source>>,
+    // anchors at which each content document is split into parts, by path,
+    // None for the part at the beginning of the document
+    anchors: HashMap<String, Vec<Option<String>>>,
+    // number of times each source occurs in the preface and the nav points
+    uses: HashMap<Source, usize>,
+}
+
+// a content document is split into all its parts at once,
+// each part is kept until it has been requested as often as the layout uses it
+#[derive(Default)]
+struct PartCache {
+    // options the parts were extracted with
+    options: Option<ParseOptions>,
+    // parts with the number of remaining uses
+    contents: HashMap<Source, (HtmlContent, usize)>,
 }
 
-impl<'a> ZipArchiveWrapper<'a> {
-    fn new(reader: Cursor<&'a [u8]>) -> Result<Self, ZipError> {
+/// Iterator over the top-level chapters of an [`EpubArchive`],
+/// extracting the content of each chapter when it is reached
+pub struct Chapters<'b, R: Read + Seek> {
+    archive: &'b EpubArchive<R>,
+    layout: ContentLayout<'b>,
+    nav_points: std::slice::Iter<'b, NavPoint>,
+    options: ParseOptions,
+}
+
+impl<'b, R: Read + Seek> Iterator for Chapters<'b, R> {
+    type Item = Result<Chapter, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let nav_point = self.nav_points.next()?;
+        Some(
+            self.archive
+                .load_chapter(nav_point, &self.layout, &self.options),
+        )
+    }
+}
+
+impl<R: Read + Seek> ZipArchiveWrapper<R> {
+    fn new(reader: R) -> Result<Self, ZipError> {
         let zip_archive = ZipArchive::new(reader)?;
         Ok(ZipArchiveWrapper { zip_archive })
     }
 
-    fn get_file_content(&mut self, filepath: &str) -> Result<String, ZipError> {
-        let mut file = self.zip_archive.by_name(filepath)?;
-        let mut buffer = String::new();
-        file.read_to_string(&mut buffer)?;
+    fn get_file_bytes(&mut self, filepath: &str) -> Result<Vec<u8>, ParseError> {
+        let error = |source: ZipError| ParseError::ZipError {
+            path: Some(filepath.to_string()),
+            source,
+        };
+        let mut file = self.zip_archive.by_name(filepath).map_err(error)?;
+        let mut buffer = Vec::with_capacity(file.size() as usize);
+        file.read_to_end(&mut buffer)
+            .map_err(|err| error(ZipError::from(err)))?;
         Ok(buffer)
     }
 
-    fn get_filenames(&self) -> Vec<String> {
-        self.zip_archive
-            .file_names()
-            .into_iter()
-            .map(|s| s.to_string())
-            .collect()
+    fn get_file_content(&mut self, filepath: &str) -> Result<String, ParseError> {
+        String::from_utf8(self.get_file_bytes(filepath)?).map_err(|source| ParseError::UTF8Error {
+            path: filepath.to_string(),
+            source,
+        })
+    }
+
+    fn has_file(&self, filepath: &str) -> bool {
+        self.zip_archive.file_names().any(|name| name == filepath)
     }
 }
 
-impl<'a> EpubArchive<'a> {
+impl<'a> EpubArchive<Cursor<&'a [u8]>> {
+    /// Open an epub file that is loaded into memory
     pub fn new(bytes: &'a [u8]) -> Result<Self, ParseError> {
-        let mut zip = ZipArchiveWrapper::new(Cursor::new(bytes))?;
-        let container_text = zip.get_file_content("META-INF/container.xml")?;
-        // TODO: make this more robust
-        let content_opf_re = Regex::new(r#"rootfile full-path="(\S*)""#).unwrap();
-
-        let content_opf_path = match content_opf_re.captures(&container_text) {
-            Some(captures) => captures.get(1).unwrap().as_str().to_string(),
-            None => {
-                return Err(ParseError::EpubError(
-                    MalformattedEpubError::MalformattedContainer,
-                ));
+        Self::from_reader(Cursor::new(bytes))
+    }
+
+    /// Open the given rendition of an epub file that is loaded into memory,
+    /// see [`EpubArchive::from_reader_with_rendition`]
+    pub fn new_with_rendition(bytes: &'a [u8], rendition: usize) -> Result<Self, ParseError> {
+        Self::from_reader_with_rendition(Cursor::new(bytes), Some(rendition))
+    }
+}
+
+impl EpubArchive<BufReader<File>> {
+    /// Open the epub file at the given path, only the needed parts of the file are read
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, ParseError> {
+        let file = File::open(path)?;
+        Self::from_reader(BufReader::new(file))
+    }
+}
+
+impl<R: Read + Seek> EpubArchive<R> {
+    /// Open an epub file from the given reader, reading its metadata and table of contents
+    ///
+    /// Of multiple renditions, the first one that is an epub package document is opened
+    pub fn from_reader(reader: R) -> Result<Self, ParseError> {
+        Self::from_reader_with_rendition(reader, None)
+    }
+
+    /// Open the given rendition of an epub file from the given reader,
+    /// as index into its [`rootfiles`](EpubArchive::rootfiles), or the default rendition if None
+    pub fn from_reader_with_rendition(
+        reader: R,
+        rendition: Option<usize>,
+    ) -> Result<Self, ParseError> {
+        let mut zip = ZipArchiveWrapper::new(reader)?;
+        let encryption = parse_encryption(&mut zip)?;
+        let container_text = zip.get_file_content(CONTAINER_PATH)?;
+        let rootfiles = parse_container(&container_text)?;
+        let rootfile = match rendition {
+            Some(rendition) => {
+                let error = |reason: String| {
+                    MalformattedEpubError::MalformattedContainer(
+                        ErrorContext::new(CONTAINER_PATH, reason)
+                            .element("rootfile")
+                            .into(),
+                    )
+                };
+                let rootfile = rootfiles
+                    .get(rendition)
+                    .ok_or_else(|| error(format!("No rendition {}", rendition)))?;
+                // other rootfiles (e.g. a pdf version) can not be parsed
+                if rootfile.media_type != PACKAGE_MEDIA_TYPE {
+                    return Err(error(format!(
+                        "Rendition {} is not a package document, media type `{}`",
+                        rendition, rootfile.media_type
+                    ))
+                    .into());
+                }
+                rootfile
             }
+            // the first rootfile is the default rendition
+            None => rootfiles
+                .iter()
+                .find(|rootfile| rootfile.media_type == PACKAGE_MEDIA_TYPE)
+                .unwrap_or(&rootfiles[0]),
         };
+        let content_opf_path = rootfile.full_path.clone();
         let content_opf_dir = match PathBuf::from(&content_opf_path).parent() {
             Some(p) => p.to_path_buf(),
             None => PathBuf::new(),
         };
         let content_opf_text = zip.get_file_content(&content_opf_path)?;
-        let content_opf = parse_content_opf(&content_opf_text)
-            .ok_or(MalformattedEpubError::MalformattedContentOpf)?;
+        let mut content_opf = parse_content_opf(&content_opf_text, &content_opf_path)?;
+        let mut warnings = std::mem::take(&mut content_opf.warnings);
+        if let Some(encryption) = &encryption {
+            warnings.extend(check_drm(encryption, &content_opf, &content_opf_dir)?);
+        }
 
-        let mut nxc_path = content_opf_dir.clone();
-        nxc_path.push(
-            &content_opf
-                .manifest
-                .get("ncx")
-                .ok_or(MalformattedEpubError::MalformattedContentOpf)?
-                .href,
-        );
-        // TODO: check if this would always work
-        let ncx_path = nxc_path.into_os_string().into_string().unwrap();
-        // println!("ncx path: {}", &ncx_path);
-        let ncx_text = zip.get_file_content(&ncx_path)?;
-        let navigation = parse_ncx(&ncx_text)?;
-
-        // construct map filename -> content for all html files declared in manifest
-        // let manifest_html_files: HashMap<String, String> = HashMap::new();
-        let manifest_html_files: HashMap<String, String> = content_opf
-            .manifest
-            .values()
-            .filter_map(|manifest_item| {
-                if manifest_item.media_type == "application/xhtml+xml" {
-                    Some(manifest_item.href.to_string())
-                } else {
-                    None
+        // if there is no usable table of contents, derive one from the spine
+        let navigation =
+            match parse_navigation(&mut zip, &content_opf, &content_opf_dir, &mut warnings) {
+                Ok(navigation) if !navigation.nav_points.is_empty() => navigation,
+                result => {
+                    let reason = match result {
+                        Ok(_) => "empty table of contents".to_string(),
+                        Err(err) => err.to_string(),
+                    };
+                    warnings.push(ParseWarning::TocFromSpine { reason });
+                    spine_to_toc(&mut zip, &content_opf, &content_opf_dir)
                 }
-            })
-            .map(|filepath| {
-                let mut full_path = content_opf_dir.clone();
-                full_path.push(filepath.clone());
-                let full_path = full_path.into_os_string().into_string().unwrap();
-                zip.get_file_content(&full_path)
-                    .map(|content| (filepath, content))
-            })
-            .collect::<Result<HashMap<_, _>, ZipError>>()?;
+            };
 
-        Ok(EpubArchive {
-            zip,
-            manifest_html_files,
+        let archive = EpubArchive {
+            zip: RefCell::new(zip),
+            rootfiles,
             content_opf_dir,
             content_opf,
             navigation,
-        })
+            encryption,
+            warnings: RefCell::new(warnings),
+            parts: RefCell::new(PartCache::default()),
+        };
+        // nav points that do not match any spine item have no content
+        for nav_point in archive.navigation.get_flattened_nav_points() {
+            let src_path = archive.resolve_src(&nav_point.src).path;
+            let in_spine = archive.content_opf.spine.iter().any(|item_id| {
+                archive
+                    .content_opf
+                    .manifest
+                    .get(item_id)
+                    .map(|item| archive.resolve_href(&item.href) == src_path)
+                    == Some(true)
+            });
+            if !in_spine {
+                archive.warn(ParseWarning::UnresolvedTocTarget {
+                    target: nav_point.src.clone(),
+                });
+            }
+        }
+        Ok(archive)
+    }
+
+    /// Renditions of the epub file as listed in META-INF/container.xml, usually only one
+    pub fn rootfiles(&self) -> &[Rootfile] {
+        &self.rootfiles
     }
 
+    /// Problems found while opening the archive and while extracting the content so far
+    pub fn warnings(&self) -> Vec<ParseWarning> {
+        self.warnings.borrow().clone()
+    }
+
+    fn warn(&self, warning: ParseWarning) {
+        let mut warnings = self.warnings.borrow_mut();
+        // content may be extracted more than once
+        if !warnings.contains(&warning) {
+            warnings.push(warning);
+        }
+    }
+
+    // read a file of the archive, reversing font obfuscation
+    fn read_bytes(&self, path: &str) -> Result<Vec<u8>, ParseError> {
+        let mut data = self.zip.borrow_mut().get_file_bytes(path)?;
+        if let Some(encryption) = &self.encryption {
+            if !encryption.deobfuscate(path, &mut data, &self.content_opf.metadata) {
+                self.warn(ParseWarning::ObfuscatedResource {
+                    path: path.to_string(),
+                });
+            }
+        }
+        Ok(data)
+    }
+
+    /// Convert the whole epub file to a book
     pub fn to_book(&self) -> Result<Book, ParseError> {
-        let (preface_content, chapters) = self.item_refs_to_chapters()?;
+        self.to_book_with_options(&ParseOptions::default())
+    }
+
+    /// Convert the whole epub file to a book, as configured by the given options
+    pub fn to_book_with_options(&self, options: &ParseOptions) -> Result<Book, ParseError> {
+        let preface = self.preface(options)?;
+        let chapters = self.chapters(options)?.collect::<Result<Vec<_>, _>>()?;
         Ok(Book {
-            title: self.content_opf.title.clone(),
-            author: self.content_opf.author.clone(),
-            preface_content,
+            title: self.content_opf.title().to_string(),
+            author: self.content_opf.author().map(String::from),
+            metadata: self.content_opf.metadata.clone(),
+            preface_content: preface.text,
+            preface_blocks: preface.blocks,
+            preface_notes: preface.notes,
             chapters,
         })
     }
 
-    pub fn get_title(&self) -> &str {
-        &self.content_opf.title
+    pub fn title(&self) -> &str {
+        self.content_opf.title()
+    }
+
+    pub fn author(&self) -> Option<&str> {
+        self.content_opf.author()
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.content_opf.metadata
+    }
+
+    /// Get the cover image, located by (in order of preference)
+    /// 1. the manifest item with the "cover-image" property (Epub 3)
+    /// 2. the manifest item referenced by <meta name="cover"> (Epub 2)
+    /// 3. the cover reference of the guide (Epub 2), or the first image of the referenced page
+    /// 4. the first image of the first spine item
+    pub fn cover(&self) -> Result<Option<Resource>, ParseError> {
+        if let Some(item) = self.content_opf.get_cover_image_item() {
+            return self.read_item(item).map(Some);
+        }
+        let guide_cover_path = self
+            .content_opf
+            .guide_cover_href
+            .as_ref()
+            .map(|href| get_full_path(&self.content_opf_dir, href.split('#').next().unwrap()));
+        // the fallbacks are guesses, files that are missing or can not be read are skipped
+        if let Some(path) = guide_cover_path {
+            let cover = if get_media_type(&self.content_opf, &self.content_opf_dir, &path)
+                .starts_with("image/")
+            {
+                self.read_file(&path).ok()
+            } else {
+                self.first_image_of(&path)
+            };
+            if cover.is_some() {
+                return Ok(cover);
+            }
+        }
+        let first_spine_path = self
+            .content_opf
+            .spine
+            .first()
+            .and_then(|item_id| self.content_opf.manifest.get(item_id))
+            .map(|item| get_full_path(&self.content_opf_dir, &item.href));
+        Ok(first_spine_path.and_then(|path| self.first_image_of(&path)))
+    }
+
+    // get the first image of the html document at the given path, if it can be read
+    fn first_image_of(&self, html_path: &str) -> Option<Resource> {
+        let html = self.zip.borrow_mut().get_file_content(html_path).ok()?;
+        let html_dir = Path::new(html_path)
+            .parent()
+            .unwrap_or_else(|| Path::new(""));
+        let src = util::get_first_image_src(&html)?;
+        // embedded and remote images are not part of the archive
+        if src.starts_with("data:") || src.contains("://") {
+            return None;
+        }
+        self.read_file(&get_full_path(html_dir, &src)).ok()
+    }
+
+    /// All resources declared in the manifest, ordered by their href
+    pub fn manifest_items(&self) -> Vec<&ManifestItem> {
+        let mut items: Vec<&ManifestItem> = self.content_opf.manifest.values().collect();
+        items.sort_by(|a, b| a.href.cmp(&b.href));
+        items
+    }
+
+    pub fn manifest_item(&self, id: &str) -> Option<&ManifestItem> {
+        self.content_opf.manifest.get(id)
+    }
+
+    /// Resolve an href relative to the package document to a path within the archive
+    pub fn resolve_href(&self, href: &str) -> String {
+        get_full_path(&self.content_opf_dir, href)
+    }
+
+    // resolve the source of a nav point, which is relative to the navigation document
+    fn resolve_src(&self, src: &str) -> Source {
+        let (file, anchor) = split_src(src);
+        let navigation_dir = Path::new(&self.navigation.path)
+            .parent()
+            .unwrap_or_else(|| Path::new(""));
+        Source {
+            path: get_full_path(navigation_dir, file),
+            anchor: anchor.map(util::percent_decode),
+        }
+    }
+
+    /// Read the content of a manifest item
+    pub fn read_item(&self, item: &ManifestItem) -> Result<Resource, ParseError> {
+        let path = self.resolve_href(&item.href);
+        let data = self.read_bytes(&path)?;
+        Ok(Resource {
+            path,
+            media_type: item.media_type.clone(),
+            data,
+        })
+    }
+
+    /// Read any file of the archive, given by its path within the archive
+    pub fn read_file(&self, path: &str) -> Result<Resource, ParseError> {
+        let data = self.read_bytes(path)?;
+        let media_type = get_media_type(&self.content_opf, &self.content_opf_dir, path);
+        Ok(Resource {
+            path: path.to_string(),
+            media_type,
+            data,
+        })
+    }
+
+    /// The nested chapter titles, without reading any content document
+    pub fn toc(&self) -> Vec<TocEntry> {
+        fn to_toc_entry(nav_point: &NavPoint) -> TocEntry {
+            TocEntry {
+                title: nav_point.label.clone().unwrap_or_default(),
+                subentries: nav_point.children.iter().map(to_toc_entry).collect(),
+            }
+        }
+        self.navigation
+            .nav_points
+            .iter()
+            .map(to_toc_entry)
+            .collect()
+    }
+
+    /// Maximum nesting level of the table of contents, 1 for a flat table of contents
+    pub fn toc_depth(&self) -> usize {
+        self.navigation.depth
+    }
+
+    /// The content before the first chapter, as a chapter without title
+    pub fn preface(&self, options: &ParseOptions) -> Result<Chapter, ParseError> {
+        let layout = self.content_layout()?;
+        let contents = layout
+            .preface
+            .iter()
+            .map(|src| self.src_to_content(src, &layout, options))
+            .collect::<Result<Vec<_>, _>>()?;
+        let content = HtmlContent::join(contents, options);
+        Ok(Chapter {
+            title: String::new(),
+            text: content.text,
+            blocks: content.blocks,
+            notes: content.notes,
+            subchapters: Vec::new(),
+        })
+    }
+
+    /// Iterate over the top-level chapters, each chapter is extracted when it is reached
+    pub fn chapters(&self, options: &ParseOptions) -> Result<Chapters<'_, R>, ParseError> {
+        Ok(Chapters {
+            archive: self,
+            layout: self.content_layout()?,
+            nav_points: self.navigation.nav_points.iter(),
+            options: options.clone(),
+        })
     }
 
-    /// find all nav points that have a source that matches the given item's href
+    /// find all nav points whose source resolves to the given item path,
+    /// with their resolved sources
     /// returns matches in same order as they appear in given list
-    fn get_matching_navpoints(
+    fn get_matching_navpoints<'n>(
         &self,
-        item_href: &str,
-        nav_points: &'a [NavPoint],
-    ) -> Vec<&'a NavPoint> {
+        item_path: &str,
+        nav_points: &'n [NavPoint],
+    ) -> Vec<(Source, &'n NavPoint)> {
         let mut result = Vec::new();
         for nav_point in nav_points {
             // nav_point src may have anchor suffix
-            if nav_point.src.contains(item_href) {
-                result.push(nav_point);
+            let source = self.resolve_src(&nav_point.src);
+            if source.path == item_path {
+                result.push((source, nav_point));
             }
-            result.append(&mut self.get_matching_navpoints(item_href, &nav_point.children));
+            result.append(&mut self.get_matching_navpoints(item_path, &nav_point.children));
         }
         result
     }
 
-    // TODO: make sure this method can not be called with zero nav_points
-    fn item_refs_to_chapters(&self) -> Result<(String, Vec<Chapter>), ParseError> {
+    fn content_layout(&self) -> Result<ContentLayout<'_>, ParseError> {
         // 1. construct list of navpoints in order they are visited by reader
-        // 2. walk through spine and add resource contents to right nav_point
-        // 3. convert nested navpoint structure to nested chapter structure
+        // 2. walk through spine and assign resources to the right nav_point
+        // the content is only read when a chapter is loaded
 
         let flattened_navpoints = self.navigation.get_flattened_nav_points();
-        // nav_point -> [src_path1 ... src_pathn], where src_path1 mave have start anchor ("path#anchor")
-        let nav_point_sources_map: HashMap<&NavPoint, Vec<&str>> = flattened_navpoints
-            .iter()
-            .map(|np| (*np, vec![np.src.as_str()]))
-            .collect();
-        // nav_point -> top level text content (not including nested nav_points' contents)
-        let mut nav_point_content_map: HashMap<&NavPoint, Vec<String>> = flattened_navpoints
-            .iter()
-            .map(|np| (*np, Vec::new()))
-            .collect();
+        let mut layout = ContentLayout {
+            preface: Vec::new(),
+            nav_points: flattened_navpoints
+                .iter()
+                .map(|np| (*np, Vec::new()))
+                .collect(),
+            anchors: HashMap::new(),
+            uses: HashMap::new(),
+        };
 
         // ordered sources that occur before first nav_point
-        let mut preface_sources: Vec<&str> = Vec::new();
-        let mut preface_content: Vec<String> = Vec::new();
+        let mut preface_sources: Vec<Source> = Vec::new();
         let mut passed_preface = false;
         // ordered (source, nav_point) pairs
-        //   where source is path with potential anchor
-        let mut ordered_sources_navpoints: Vec<(&str, &NavPoint)> =
+        let mut ordered_sources_navpoints: Vec<(Source, &NavPoint)> =
             Vec::with_capacity(self.content_opf.spine.len());
 
-        let mut last_matched_nav_point = *flattened_navpoints.first().unwrap();
+        let mut last_matched_nav_point = match flattened_navpoints.first() {
+            Some(nav_point) => *nav_point,
+            None => return Ok(layout),
+        };
         for item_id in &self.content_opf.spine {
             // convert id to href
             let item_href = self
@@ -234,119 +674,328 @@ impl<'a> EpubArchive<'a> {
                 .manifest
                 .get(item_id)
                 .map(|manifest_item| &manifest_item.href)
-                .ok_or(ParseError::EpubError(
-                    MalformattedEpubError::MalformattedContentOpf,
-                ))?;
+                .ok_or_else(|| {
+                    MalformattedEpubError::MalformattedManifest(
+                        ErrorContext::new(
+                            &self.content_opf.path,
+                            format!("Spine item `{}` not in manifest", item_id),
+                        )
+                        .element("itemref")
+                        .attribute("idref")
+                        .into(),
+                    )
+                })?;
+            let item_path = self.resolve_href(item_href);
             let matching_nav_points =
-                self.get_matching_navpoints(item_href, &self.navigation.nav_points);
+                self.get_matching_navpoints(&item_path, &self.navigation.nav_points);
             // if no matches
             if matching_nav_points.is_empty() {
+                let source = Source {
+                    path: item_path,
+                    anchor: None,
+                };
                 // if beyond preface, should match previous nav_point
                 // else, matches preface
                 if passed_preface {
-                    ordered_sources_navpoints.push((item_href, last_matched_nav_point));
+                    ordered_sources_navpoints.push((source, last_matched_nav_point));
                 } else {
-                    preface_sources.push(item_href);
+                    preface_sources.push(source);
                 }
             } else {
                 passed_preface = true;
                 // if some matches,
                 // append matched nav_points' sources in order
-                for matching_nav_point in matching_nav_points {
-                    let first_src_of_np = *nav_point_sources_map
-                        .get(matching_nav_point)
-                        .unwrap()
-                        .first()
-                        .unwrap();
-                    ordered_sources_navpoints.push((first_src_of_np, matching_nav_point));
+                for (source, matching_nav_point) in matching_nav_points {
+                    ordered_sources_navpoints.push((source, matching_nav_point));
                     last_matched_nav_point = matching_nav_point;
                 }
             }
         }
 
-        // fill in contents for nav_points
-        for i in 0..ordered_sources_navpoints.len() {
-            let (src_path, nav_point) = ordered_sources_navpoints.get(i).unwrap();
-            let next_src_path = ordered_sources_navpoints.get(i + 1).map(|(s, _np)| *s);
-            let content_chunk = self.src_to_text(src_path, next_src_path)?;
-            nav_point_content_map
-                .get_mut(nav_point)
-                .unwrap()
-                .push(content_chunk);
-        }
-        // fill in content for preface
-        for i in 0..preface_sources.len() {
-            let src_path = preface_sources.get(i).unwrap();
-            let next_src_path = if i == preface_sources.len() - 1 {
-                preface_sources.last().copied()
-            } else {
-                ordered_sources_navpoints.first().map(|tpl_ref| tpl_ref.0)
-            };
-            let content_chunk = self.src_to_text(src_path, next_src_path)?;
-            preface_content.push(content_chunk);
+        for source in preface_sources
+            .iter()
+            .chain(ordered_sources_navpoints.iter().map(|(source, _np)| source))
+        {
+            let anchors = layout.anchors.entry(source.path.clone()).or_default();
+            if !anchors.contains(&source.anchor) {
+                anchors.push(source.anchor.clone());
+            }
+            *layout.uses.entry(source.clone()).or_default() += 1;
         }
+        // sources of the nav_points' top level content
+        for (source, nav_point) in ordered_sources_navpoints {
+            layout.nav_points.get_mut(nav_point).unwrap().push(source);
+        }
+        layout.preface = preface_sources;
+        Ok(layout)
+    }
 
-        let chapters: Vec<Chapter> = self
-            .navigation
+    // extract the content of a nav point and its children
+    fn load_chapter(
+        &self,
+        nav_point: &NavPoint,
+        layout: &ContentLayout,
+        options: &ParseOptions,
+    ) -> Result<Chapter, ParseError> {
+        let contents = layout
             .nav_points
+            .get(nav_point)
+            .map(|sources| sources.as_slice())
+            .unwrap_or_default()
             .iter()
-            .map(|np| convert_np_to_chapter(np, &nav_point_content_map))
-            .collect();
-        Ok((preface_content.join("\n"), chapters))
-    }
-
-    // get text starting at src (path with potential anchor)
-    // end of text is
-    // a. end of file given by src (if next_src is different file)
-    // b. before anchor in next_src (if next_src is same file)
-    fn src_to_text(&self, src: &str, next_src: Option<&str>) -> Result<String, ParseError> {
-        let mut src_split = src.split('#');
-        let src_file = src_split.next().unwrap();
-        let src_anchor = src_split.next();
-        let full_text = self.manifest_html_files.get(src_file).ok_or_else(|| {
-            MalformattedEpubError::MalformattedTocNcx(format!(
-                "File {} in TOC, but not in Manifest",
-                src_file
-            ))
-        })?;
-        let stop_anchor = if next_src.map(|s| s.starts_with(src_file)) == Some(true) {
-            let mut next_src_split = next_src.unwrap().split('#');
-            let _next_src_file = next_src_split.next();
-            next_src_split.next()
-        } else {
-            None
-        };
-        util::html_to_text(full_text.as_str(), src_anchor, stop_anchor).map_err(|err| {
-            ParseError::EpubError(MalformattedEpubError::MalformattedHTML(
-                src_file.to_string(),
-                err,
-            ))
+            .map(|src| self.src_to_content(src, layout, options))
+            .collect::<Result<Vec<_>, _>>()?;
+        let subchapters = nav_point
+            .children
+            .iter()
+            .map(|child| self.load_chapter(child, layout, options))
+            .collect::<Result<Vec<_>, _>>()?;
+        let content = HtmlContent::join(contents, options);
+        Ok(Chapter {
+            title: nav_point.label.clone().unwrap_or_default(),
+            text: content.text,
+            blocks: content.blocks,
+            notes: content.notes,
+            subchapters,
         })
     }
+
+    // get content starting at the source,
+    // until the next part of the same file given by the layout (or the end of the file)
+    fn src_to_content(
+        &self,
+        source: &Source,
+        layout: &ContentLayout,
+        options: &ParseOptions,
+    ) -> Result<HtmlContent, ParseError> {
+        {
+            let mut parts = self.parts.borrow_mut();
+            if parts.options.as_ref() == Some(options) {
+                if let Some((content, remaining)) = parts.contents.get_mut(source) {
+                    *remaining -= 1;
+                    if *remaining > 0 {
+                        return Ok(content.clone());
+                    }
+                    return Ok(parts.contents.remove(source).unwrap().0);
+                }
+            } else {
+                parts.contents.clear();
+                parts.options = Some(options.clone());
+            }
+        }
+        let is_html_item = self.content_opf.manifest.values().any(|item| {
+            item.media_type == "application/xhtml+xml"
+                && self.resolve_href(&item.href) == source.path
+        });
+        if !is_html_item {
+            return Err(ParseError::EpubError(
+                MalformattedEpubError::MalformattedManifest(
+                    ErrorContext::new(
+                        &self.navigation.path,
+                        format!("File `{}` in TOC, but not in manifest", source.path),
+                    )
+                    .into(),
+                ),
+            ));
+        }
+        let path = &source.path;
+        let full_text = self.zip.borrow_mut().get_file_content(path)?;
+        let mut anchors = layout.anchors.get(path).cloned().unwrap_or_default();
+        if !anchors.contains(&source.anchor) {
+            anchors.push(source.anchor.clone());
+        }
+        let anchor_refs: Vec<Option<&str>> = anchors.iter().map(Option::as_deref).collect();
+        let contents = util::html_to_contents(
+            full_text.as_str(),
+            &anchor_refs,
+            Some(self.content_opf.language()),
+            options,
+        );
+        let mut result = None;
+        let mut parts = self.parts.borrow_mut();
+        for (anchor, mut content) in anchors.into_iter().zip(contents) {
+            for warning in content.warnings.drain(..) {
+                self.warn(warning.into_parse_warning(path));
+            }
+            let part = Source {
+                path: path.clone(),
+                anchor,
+            };
+            let mut uses = layout.uses.get(&part).copied().unwrap_or_default();
+            if part == *source {
+                uses = uses.saturating_sub(1);
+                if uses == 0 {
+                    result = Some(content);
+                    continue;
+                }
+                result = Some(content.clone());
+            }
+            if uses > 0 {
+                parts.contents.insert(part, (content, uses));
+            }
+        }
+        Ok(result.unwrap())
+    }
 }
 
-// given nav_point must be present in content map
-fn convert_np_to_chapter(
-    nav_point: &NavPoint,
-    contents: &HashMap<&NavPoint, Vec<String>>,
-) -> Chapter {
-    let subchapters: Vec<Chapter> = nav_point
-        .children
+// split a source into the path and the anchor
+fn split_src(src: &str) -> (&str, Option<&str>) {
+    let mut src_split = src.splitn(2, '#');
+    (src_split.next().unwrap(), src_split.next())
+}
+
+// join a path relative to the given directory (usually the content.opf directory)
+// into a normalized path within the zip archive, hrefs are percent-decoded
+pub(crate) fn get_full_path(dir: &Path, href: &str) -> String {
+    let dir = dir.to_string_lossy();
+    let href = util::percent_decode(href);
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in dir.split(['/', '\\']).chain(href.split('/')) {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    segments.join("/")
+}
+
+// media type of the file at the given path within the zip archive,
+// as declared in the manifest or guessed from the file extension
+fn get_media_type(content_opf: &ContentOPF, content_opf_dir: &Path, path: &str) -> String {
+    let manifest_media_type = content_opf
+        .manifest
+        .values()
+        .find(|item| get_full_path(content_opf_dir, &item.href) == path)
+        .map(|item| item.media_type.clone());
+    manifest_media_type.unwrap_or_else(|| {
+        let extension = path.rsplit('.').next().unwrap_or_default();
+        match extension.to_lowercase().as_str() {
+            "jpg" | "jpeg" => "image/jpeg",
+            "png" => "image/png",
+            "gif" => "image/gif",
+            "svg" => "image/svg+xml",
+            "webp" => "image/webp",
+            "xhtml" | "html" | "htm" => "application/xhtml+xml",
+            "css" => "text/css",
+            _ => "application/octet-stream",
+        }
+        .to_string()
+    })
+}
+
+// prefer the Epub 3 navigation document, use the Epub 2 toc.ncx as fallback
+// if the navigation document is missing, invalid or empty
+fn parse_navigation(
+    zip: &mut ZipArchiveWrapper<impl Read + Seek>,
+    content_opf: &ContentOPF,
+    content_opf_dir: &Path,
+    warnings: &mut Vec<ParseWarning>,
+) -> Result<TocNcx, ParseError> {
+    let nav_doc_result = content_opf.get_nav_item().map(|nav_item| {
+        let nav_doc_path = get_full_path(content_opf_dir, &nav_item.href);
+        let nav_doc_text = zip
+            .get_file_content(&nav_doc_path)
+            .map_err(|err| (nav_doc_path.clone(), err))?;
+        match parse_nav_doc(&nav_doc_text, &nav_doc_path) {
+            Ok(navigation) if navigation.nav_points.is_empty() => {
+                let context =
+                    ErrorContext::new(&nav_doc_path, "Empty table of contents").element("ol");
+                Err((
+                    nav_doc_path,
+                    MalformattedEpubError::MalformattedNavDoc(context.into()).into(),
+                ))
+            }
+            Ok(navigation) => Ok(navigation),
+            Err(err) => Err((nav_doc_path, err.into())),
+        }
+    });
+    let nav_doc_err = match nav_doc_result {
+        Some(Ok(navigation)) => return Ok(navigation),
+        Some(Err(err)) => Some(err),
+        None => None,
+    };
+    let ncx_item = match (content_opf.get_ncx_item(), nav_doc_err) {
+        (Some(ncx_item), Some((path, err))) => {
+            warnings.push(ParseWarning::UnusableNavDoc {
+                path,
+                reason: err.to_string(),
+            });
+            ncx_item
+        }
+        (Some(ncx_item), None) => ncx_item,
+        (None, nav_doc_err) => {
+            return Err(nav_doc_err
+                .map(|(_path, err)| err)
+                .unwrap_or(ParseError::EpubError(
+                    MalformattedEpubError::MalformattedManifest(
+                        ErrorContext::new(
+                            &content_opf.path,
+                            "No navigation document or toc.ncx in manifest",
+                        )
+                        .element("item")
+                        .into(),
+                    ),
+                )))
+        }
+    };
+    let ncx_path = get_full_path(content_opf_dir, &ncx_item.href);
+    let ncx_text = zip.get_file_content(&ncx_path)?;
+    Ok(parse_ncx(&ncx_text, &ncx_path)?)
+}
+
+// create a flat table of contents with one nav point per html spine item,
+// titled by the item's first heading or title
+fn spine_to_toc(
+    zip: &mut ZipArchiveWrapper<impl Read + Seek>,
+    content_opf: &ContentOPF,
+    content_opf_dir: &Path,
+) -> TocNcx {
+    let mut seen_hrefs = HashSet::new();
+    let nav_points: Vec<NavPoint> = content_opf
+        .spine
         .iter()
-        .map(|child| convert_np_to_chapter(child, contents))
+        .filter_map(|item_id| content_opf.manifest.get(item_id))
+        .filter(|item| item.media_type == "application/xhtml+xml")
+        .filter(|item| seen_hrefs.insert(&item.href))
+        .filter_map(|item| {
+            let html = zip
+                .get_file_content(&get_full_path(content_opf_dir, &item.href))
+                .ok()?;
+            Some((item, html))
+        })
+        .enumerate()
+        .map(|(i, (item, html))| NavPoint {
+            id: item.id.clone(),
+            label: util::get_html_title(&html),
+            play_order: Some(i + 1),
+            level: 1,
+            src: item.href.clone(),
+            children: Vec::new(),
+        })
         .collect();
-    let text = contents
-        .get(nav_point)
-        .expect("NavPoint should have been present in content map")
-        .join("\n");
-    Chapter {
-        title: nav_point.label.clone().unwrap_or_else(|| "".to_string()),
-        text,
-        subchapters,
+    TocNcx {
+        path: content_opf.path.clone(),
+        depth: 1,
+        nav_points,
     }
 }
 
-fn parse_nav_points(nav_points: &Element, level: usize) -> Option<Vec<NavPoint>> {
+fn parse_nav_points(
+    nav_points: &Element,
+    level: usize,
+    path: &str,
+) -> Result<Vec<NavPoint>, MalformattedEpubError> {
+    let error = |reason: String, element: &str, attribute: &str| {
+        MalformattedEpubError::MalformattedTocNcx(
+            ErrorContext::new(path, reason)
+                .element(element)
+                .attribute(attribute)
+                .into(),
+        )
+    };
     nav_points
         .children
         .iter()
@@ -359,19 +1008,33 @@ fn parse_nav_points(nav_points: &Element, level: usize) -> Option<Vec<NavPoint>>
             None
         })
         .map(|el| {
-            let id = el.attributes.get("id")?.to_string();
+            let id = el
+                .attributes
+                .get("id")
+                .ok_or_else(|| error("Missing nav point id".to_string(), "navPoint", "id"))?
+                .to_string();
             let play_order: Option<usize> = el
                 .attributes
                 .get("playOrder")
                 .and_then(|po| po.parse().ok());
-            let src = el.get_child("content")?.attributes.get("src")?.to_string();
+            let src = el
+                .get_child("content")
+                .and_then(|content| content.attributes.get("src"))
+                .ok_or_else(|| {
+                    error(
+                        format!("Nav point `{}` has no content source", id),
+                        "content",
+                        "src",
+                    )
+                })?
+                .to_string();
             let label = el
                 .get_child("navLabel")
                 .and_then(|el| el.get_child("text"))
                 .and_then(|el| el.get_text())
                 .map(|s| s.to_string());
-            let children = parse_nav_points(el, level + 1)?;
-            Some(NavPoint {
+            let children = parse_nav_points(el, level + 1, path)?;
+            Ok(NavPoint {
                 id,
                 label,
                 play_order,
@@ -380,15 +1043,16 @@ fn parse_nav_points(nav_points: &Element, level: usize) -> Option<Vec<NavPoint>>
                 children,
             })
         })
-        .collect::<Option<Vec<_>>>()
+        .collect()
 }
 
-fn parse_ncx(text: &str) -> Result<TocNcx, MalformattedEpubError> {
-    let ncx = xmltree::Element::parse_with_config(text.as_bytes(), get_parser_config())
-        .map_err(|_e| MalformattedEpubError::MalformattedTocNcx("Invalid XML".to_string()))?;
+fn parse_ncx(text: &str, path: &str) -> Result<TocNcx, MalformattedEpubError> {
+    let error = |context: ErrorContext| MalformattedEpubError::MalformattedTocNcx(context.into());
+    let ncx = xmltree::Element::parse_with_config(text.as_bytes(), get_parser_config(text))
+        .map_err(|err| error(xml_error_context(path, err)))?;
     let depths: Vec<usize> = ncx
         .get_child("head")
-        .ok_or_else(|| MalformattedEpubError::MalformattedTocNcx("Missing head".to_string()))?
+        .ok_or_else(|| error(ErrorContext::new(path, "Missing head").element("head")))?
         .children
         .iter()
         .filter_map(|node| {
@@ -404,107 +1068,775 @@ fn parse_ncx(text: &str) -> Result<TocNcx, MalformattedEpubError> {
         })
         .collect();
     let depth = if depths.len() != 1 {
-        return Err(MalformattedEpubError::MalformattedTocNcx(
-            "Depth info missing or duplicated".to_string(),
+        return Err(error(
+            ErrorContext::new(path, "Depth info missing or duplicated").element("meta"),
         ));
     } else {
-        *depths.get(0).unwrap()
+        *depths.first().unwrap()
     };
     let nav_map = ncx
         .get_child("navMap")
-        .ok_or_else(|| MalformattedEpubError::MalformattedTocNcx("Missing navMap".to_string()))?;
-    let nav_points = parse_nav_points(nav_map, 1).ok_or_else(|| {
-        MalformattedEpubError::MalformattedTocNcx("Could not parse NavPoints".to_string())
+        .ok_or_else(|| error(ErrorContext::new(path, "Missing navMap").element("navMap")))?;
+    let nav_points = parse_nav_points(nav_map, 1, path)?;
+    Ok(TocNcx {
+        path: path.to_string(),
+        depth,
+        nav_points,
+    })
+}
+
+fn parse_nav_list(
+    list: &Element,
+    level: usize,
+    play_order: &mut usize,
+    path: &str,
+) -> Result<Vec<NavPoint>, MalformattedEpubError> {
+    let mut nav_points = Vec::new();
+    for li in list.children.iter().filter_map(|node| node.as_element()) {
+        if li.name != "li" {
+            continue;
+        }
+        *play_order += 1;
+        let id = li
+            .attributes
+            .get("id")
+            .cloned()
+            .unwrap_or_else(|| format!("navpoint-{}", play_order));
+        let current_play_order = *play_order;
+        // a list item is labelled either by a link or, for unlinked headings, a span
+        let label_el = li
+            .children
+            .iter()
+            .filter_map(|node| node.as_element())
+            .find(|el| el.name == "a" || el.name == "span")
+            .ok_or_else(|| {
+                MalformattedEpubError::MalformattedNavDoc(
+                    ErrorContext::new(path, format!("List item `{}` has no label", id))
+                        .element("li")
+                        .into(),
+                )
+            })?;
+        let label = util::get_all_text(label_el);
+        let children = match li.get_child("ol") {
+            Some(ol) => parse_nav_list(ol, level + 1, play_order, path)?,
+            None => Vec::new(),
+        };
+        // unlinked headings point to the start of their first child
+        let src = match label_el.attributes.get("href") {
+            Some(href) => href.to_string(),
+            None => children.first().map(|np| np.src.clone()).ok_or_else(|| {
+                MalformattedEpubError::MalformattedNavDoc(
+                    ErrorContext::new(
+                        path,
+                        format!("List item `{}` has neither link nor children", id),
+                    )
+                    .element("a")
+                    .attribute("href")
+                    .into(),
+                )
+            })?,
+        };
+        nav_points.push(NavPoint {
+            id,
+            label: Some(label).filter(|s| !s.is_empty()),
+            play_order: Some(current_play_order),
+            level,
+            src,
+            children,
+        });
+    }
+    Ok(nav_points)
+}
+
+// find the <nav epub:type="toc"> element with depth first search
+fn find_toc_nav(element: &Element) -> Option<&Element> {
+    let is_toc = element.name == "nav"
+        && element
+            .attributes
+            .get("type")
+            .map(|t| t.split_whitespace().any(|token| token == "toc"))
+            == Some(true);
+    if is_toc {
+        return Some(element);
+    }
+    element
+        .children
+        .iter()
+        .filter_map(|node| node.as_element())
+        .find_map(find_toc_nav)
+}
+
+fn get_depth(nav_points: &[NavPoint]) -> usize {
+    nav_points
+        .iter()
+        .map(|np| np.level.max(get_depth(&np.children)))
+        .max()
+        .unwrap_or(0)
+}
+
+fn parse_nav_doc(text: &str, path: &str) -> Result<TocNcx, MalformattedEpubError> {
+    let error = |context: ErrorContext| MalformattedEpubError::MalformattedNavDoc(context.into());
+    let nav_doc = util::parse_html(text);
+    let toc_nav = find_toc_nav(&nav_doc).ok_or_else(|| {
+        error(
+            ErrorContext::new(path, "Missing toc nav")
+                .element("nav")
+                .attribute("epub:type"),
+        )
     })?;
-    Ok(TocNcx { depth, nav_points })
+    let list = toc_nav
+        .get_child("ol")
+        .ok_or_else(|| error(ErrorContext::new(path, "Missing list").element("ol")))?;
+    let nav_points = parse_nav_list(list, 1, &mut 0, path)?;
+    let depth = get_depth(&nav_points);
+    Ok(TocNcx {
+        path: path.to_string(),
+        depth,
+        nav_points,
+    })
 }
 
-fn parse_manifest(manifest: &Element) -> Manifest {
-    manifest
+// items without id, href or media type are skipped
+fn parse_manifest(manifest: &Element) -> (Manifest, Vec<ParseWarning>) {
+    let mut items = Manifest::new();
+    let mut warnings = Vec::new();
+    for el in manifest
         .children
         .iter()
-        .filter_map(|node| {
-            if let Some(el) = node.as_element() {
-                if el.name == "item" {
-                    let id = el.attributes.get("id")?.to_string();
-                    return Some((
-                        id.clone(),
-                        ManifestItem {
-                            id,
-                            href: el.attributes.get("href")?.to_string(),
-                            media_type: el.attributes.get("media-type")?.to_string(),
-                            properties: el.attributes.get("properties").map(|s| s.to_string()),
-                        },
-                    ));
-                }
+        .filter_map(|node| node.as_element())
+    {
+        if el.name != "item" {
+            continue;
+        }
+        let attribute = |name: &str| el.attributes.get(name).cloned();
+        match (attribute("id"), attribute("href"), attribute("media-type")) {
+            (Some(id), Some(href), Some(media_type)) => {
+                items.insert(
+                    id.clone(),
+                    ManifestItem {
+                        id,
+                        href,
+                        media_type,
+                        properties: attribute("properties"),
+                    },
+                );
             }
-            None
+            (id, href, _) => {
+                let missing = if id.is_none() {
+                    "id"
+                } else if href.is_none() {
+                    "href"
+                } else {
+                    "media-type"
+                };
+                warnings.push(ParseWarning::SkippedManifestItem {
+                    id: id.or(href).unwrap_or_default(),
+                    attribute: missing.to_string(),
+                });
+            }
+        }
+    }
+    (items, warnings)
+}
+
+pub fn parse_spine(spine: &Element, path: &str) -> Result<Spine, MalformattedEpubError> {
+    spine
+        .children
+        .iter()
+        .filter_map(|node| node.as_element())
+        .filter(|el| el.name == "itemref")
+        .map(|el| {
+            el.attributes.get("idref").cloned().ok_or_else(|| {
+                MalformattedEpubError::MalformattedContentOpf(
+                    ErrorContext::new(path, "Spine item without idref")
+                        .element("itemref")
+                        .attribute("idref")
+                        .into(),
+                )
+            })
         })
-        .collect::<HashMap<ItemId, ManifestItem>>()
+        .collect()
+}
+
+// Epub 3 meta elements that refine other metadata elements, by the refined element's id
+fn get_refinements(metadata: &Element) -> HashMap<&str, Vec<&Element>> {
+    let mut refinements: HashMap<&str, Vec<&Element>> = HashMap::new();
+    for el in metadata
+        .children
+        .iter()
+        .filter_map(|node| node.as_element())
+    {
+        if el.name != "meta" {
+            continue;
+        }
+        if let Some(refined_id) = el
+            .attributes
+            .get("refines")
+            .and_then(|refines| refines.strip_prefix('#'))
+        {
+            refinements.entry(refined_id).or_default().push(el);
+        }
+    }
+    refinements
+}
+
+fn get_refinement<'e>(
+    refinements: &HashMap<&str, Vec<&'e Element>>,
+    el: &Element,
+    property: &str,
+) -> Option<&'e Element> {
+    let id = el.attributes.get("id")?;
+    refinements
+        .get(id.as_str())?
+        .iter()
+        .find(|meta| meta.attributes.get("property").map(String::as_str) == Some(property))
+        .copied()
 }
 
-pub fn parse_spine(spine: &Element) -> Option<Spine> {
-    Some(
-        spine
+fn get_trimmed_text(el: &Element) -> Option<String> {
+    let text = el.get_text()?;
+    let text = text.trim();
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_string())
+    }
+}
+
+// creators or contributors with their Epub 2 attributes or Epub 3 refinements,
+// ordered by display sequence (contributors without sequence keep their position at the end)
+fn parse_contributors(
+    metadata: &Element,
+    refinements: &HashMap<&str, Vec<&Element>>,
+    name: &str,
+) -> Vec<Contributor> {
+    let refined_texts = |el: &Element, property: &str| -> Vec<String> {
+        el.attributes
+            .get("id")
+            .and_then(|id| refinements.get(id.as_str()))
+            .map(|metas| {
+                metas
+                    .iter()
+                    .filter(|meta| {
+                        meta.attributes.get("property").map(String::as_str) == Some(property)
+                    })
+                    .filter_map(|meta| get_trimmed_text(meta))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let mut contributors: Vec<Contributor> = metadata
+        .children
+        .iter()
+        .filter_map(|node| node.as_element())
+        .filter(|el| el.name == name)
+        .filter_map(|el| {
+            let mut roles: Vec<String> = el.attributes.get("role").cloned().into_iter().collect();
+            roles.extend(refined_texts(el, "role"));
+            let file_as = el
+                .attributes
+                .get("file-as")
+                .cloned()
+                .or_else(|| refined_texts(el, "file-as").into_iter().next());
+            let display_seq = refined_texts(el, "display-seq")
+                .first()
+                .and_then(|seq| seq.parse().ok());
+            Some(Contributor {
+                name: get_trimmed_text(el)?,
+                roles,
+                file_as,
+                display_seq,
+            })
+        })
+        .collect();
+    contributors.sort_by_key(|contributor| contributor.display_seq.unwrap_or(u32::MAX));
+    contributors
+}
+
+fn parse_metadata(metadata: &Element, unique_identifier_id: Option<&str>) -> Metadata {
+    let refinements = get_refinements(metadata);
+    let elements = || {
+        metadata
             .children
             .iter()
-            .filter_map(|node| {
-                if let Some(el) = node.as_element() {
-                    if el.name == "itemref" {
-                        let id = el.attributes.get("idref")?.to_string();
-                        return Some(id);
-                    }
+            .filter_map(|node| node.as_element())
+    };
+    let texts = |name: &str| -> Vec<String> {
+        elements()
+            .filter(|el| el.name == name)
+            .filter_map(get_trimmed_text)
+            .collect()
+    };
+    let identifiers: Vec<Identifier> = elements()
+        .filter(|el| el.name == "identifier")
+        .filter_map(|el| {
+            let scheme = el.attributes.get("scheme").cloned().or_else(|| {
+                let meta = get_refinement(&refinements, el, "identifier-type")?;
+                let identifier_type = get_trimmed_text(meta)?;
+                // ONIX product identifier types
+                match (
+                    meta.attributes.get("scheme").map(String::as_str),
+                    identifier_type.as_str(),
+                ) {
+                    (Some("onix:codelist5"), "02") => Some("ISBN-10".to_string()),
+                    (Some("onix:codelist5"), "15") => Some("ISBN-13".to_string()),
+                    _ => Some(identifier_type),
                 }
-                None
+            });
+            Some(Identifier {
+                id: el.attributes.get("id").cloned(),
+                scheme,
+                value: get_trimmed_text(el)?,
             })
-            .collect(),
+        })
+        .collect();
+    let unique_identifier = identifiers
+        .iter()
+        .find(|identifier| {
+            identifier.id.is_some() && identifier.id.as_deref() == unique_identifier_id
+        })
+        .map(|identifier| identifier.value.clone());
+    let mut dates: Vec<Date> = elements()
+        .filter(|el| el.name == "date")
+        .filter_map(|el| {
+            Some(Date {
+                event: el.attributes.get("event").cloned(),
+                value: get_trimmed_text(el)?,
+            })
+        })
+        .collect();
+    dates.extend(
+        elements()
+            .filter(|el| {
+                el.name == "meta"
+                    && el.attributes.get("property").map(String::as_str) == Some("dcterms:modified")
+            })
+            .filter_map(|el| {
+                Some(Date {
+                    event: Some("modification".to_string()),
+                    value: get_trimmed_text(el)?,
+                })
+            }),
+    );
+    Metadata {
+        identifiers,
+        unique_identifier,
+        titles: texts("title"),
+        creators: parse_contributors(metadata, &refinements, "creator"),
+        contributors: parse_contributors(metadata, &refinements, "contributor"),
+        publisher: texts("publisher").into_iter().next(),
+        dates,
+        description: texts("description").into_iter().next(),
+        subjects: texts("subject"),
+        rights: texts("rights").into_iter().next(),
+        source: texts("source").into_iter().next(),
+        languages: texts("language"),
+    }
+}
+
+// manifest id of the Epub 2 cover image
+fn parse_cover_meta(metadata: &Element) -> Option<String> {
+    metadata
+        .children
+        .iter()
+        .filter_map(|node| node.as_element())
+        .find(|el| {
+            el.name == "meta" && el.attributes.get("name").map(String::as_str) == Some("cover")
+        })
+        .and_then(|el| el.attributes.get("content"))
+        .cloned()
+}
+
+// href of the Epub 2 guide's cover reference
+fn parse_guide_cover(guide: &Element) -> Option<String> {
+    guide
+        .children
+        .iter()
+        .filter_map(|node| node.as_element())
+        .find(|el| {
+            el.name == "reference"
+                && el
+                    .attributes
+                    .get("type")
+                    .map(|t| t.eq_ignore_ascii_case("cover"))
+                    == Some(true)
+        })
+        .and_then(|el| el.attributes.get("href"))
+        .cloned()
+}
+
+// encrypted resources declared in META-INF/encryption.xml, if the file exists
+fn parse_encryption(
+    zip: &mut ZipArchiveWrapper<impl Read + Seek>,
+) -> Result<Option<Encryption>, ParseError> {
+    if !zip.has_file(ENCRYPTION_PATH) {
+        return Ok(None);
+    }
+    let encryption_text = zip.get_file_content(ENCRYPTION_PATH)?;
+    let encryption_xml = xmltree::Element::parse_with_config(
+        encryption_text.as_bytes(),
+        get_parser_config(&encryption_text),
     )
+    .map_err(|err| {
+        MalformattedEpubError::MalformattedEncryption(
+            xml_error_context(ENCRYPTION_PATH, err).into(),
+        )
+    })?;
+    // rights.xml only helps to classify the encryption, so it may be missing or invalid
+    let rights_xml = if zip.has_file(RIGHTS_PATH) {
+        let rights_text = zip.get_file_content(RIGHTS_PATH)?;
+        xmltree::Element::parse_with_config(rights_text.as_bytes(), get_parser_config(&rights_text))
+            .ok()
+    } else {
+        None
+    };
+    Ok(Some(Encryption::new(
+        &encryption_xml,
+        rights_xml.as_ref(),
+        |path| zip.has_file(path),
+    )))
+}
+
+// encrypted content documents can not be parsed, only obfuscated fonts are readable
+// other encrypted resources (e.g. images) are reported as warnings
+fn check_drm(
+    encryption: &Encryption,
+    content_opf: &ContentOPF,
+    content_opf_dir: &Path,
+) -> Result<Vec<ParseWarning>, ParseError> {
+    let content_paths: HashSet<String> = content_opf
+        .manifest
+        .values()
+        .filter(|item| {
+            item.media_type == "application/xhtml+xml" || content_opf.spine.contains(&item.id)
+        })
+        .map(|item| get_full_path(content_opf_dir, &item.href))
+        .collect();
+    let mut drm_paths: Vec<&str> = encryption.drm_protected().collect();
+    drm_paths.sort_unstable();
+    if let Some(path) = drm_paths.iter().find(|path| content_paths.contains(**path)) {
+        return Err(ParseError::DrmProtected {
+            scheme: encryption.scheme,
+            path: path.to_string(),
+        });
+    }
+    Ok(drm_paths
+        .into_iter()
+        .map(|path| ParseWarning::EncryptedResource {
+            path: path.to_string(),
+            scheme: encryption.scheme,
+        })
+        .collect())
+}
+
+// context of an xml document that could not be parsed, with the position of the failure
+fn xml_error_context(path: &str, err: xmltree::ParseError) -> ErrorContext {
+    match err {
+        xmltree::ParseError::MalformedXml(err) => {
+            ErrorContext::new(path, format!("Invalid XML, {}", err.msg())).position(err.position())
+        }
+        xmltree::ParseError::CannotParse => ErrorContext::new(path, "Invalid XML"),
+    }
 }
 
-fn parse_content_opf(text: &str) -> Option<ContentOPF> {
-    let package = xmltree::Element::parse_with_config(text.as_bytes(), get_parser_config()).ok()?;
-    let metadata = package.get_child("metadata")?;
-    let manifest = package.get_child("manifest")?;
-    let spine = package.get_child("spine")?;
-    let title = metadata.get_child("title")?.get_text()?.to_string();
-    let author = metadata
-        .get_child("creator")
-        .map(|el| el.get_text())
-        .flatten()
-        .map(|s| s.to_string());
-    let language = metadata.get_child("language")?.get_text()?.to_string();
-    let manifest = parse_manifest(manifest);
-    let spine = parse_spine(spine)?;
-    Some(ContentOPF {
-        title,
-        author,
-        language,
+// list the rootfiles of container.xml, there is at least one
+fn parse_container(text: &str) -> Result<Vec<Rootfile>, MalformattedEpubError> {
+    let error =
+        |context: ErrorContext| MalformattedEpubError::MalformattedContainer(context.into());
+    let container = xmltree::Element::parse_with_config(text.as_bytes(), get_parser_config(text))
+        .map_err(|err| error(xml_error_context(CONTAINER_PATH, err)))?;
+    let rootfiles = container
+        .get_child("rootfiles")
+        .map(|rootfiles| rootfiles.children.as_slice())
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|node| node.as_element())
+        .filter(|el| el.name == "rootfile")
+        .map(|el| {
+            let full_path = el
+                .attributes
+                .get("full-path")
+                .map(|full_path| full_path.trim().to_string())
+                .filter(|full_path| !full_path.is_empty())
+                .ok_or_else(|| {
+                    error(
+                        ErrorContext::new(CONTAINER_PATH, "Rootfile without path")
+                            .element("rootfile")
+                            .attribute("full-path"),
+                    )
+                })?;
+            let attribute = |name: &str| el.attributes.get(name).cloned();
+            Ok(Rootfile {
+                full_path,
+                media_type: attribute("media-type").unwrap_or_default(),
+                label: attribute("label"),
+                layout: attribute("layout"),
+                language: attribute("language"),
+                media: attribute("media"),
+                access_mode: attribute("accessMode"),
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if rootfiles.is_empty() {
+        return Err(error(
+            ErrorContext::new(CONTAINER_PATH, "No package document given").element("rootfile"),
+        ));
+    }
+    Ok(rootfiles)
+}
+
+fn parse_content_opf(text: &str, path: &str) -> Result<ContentOPF, MalformattedEpubError> {
+    let error =
+        |context: ErrorContext| MalformattedEpubError::MalformattedContentOpf(context.into());
+    let missing = |element: &str| {
+        error(ErrorContext::new(path, format!("Missing {}", element)).element(element))
+    };
+    let package = xmltree::Element::parse_with_config(text.as_bytes(), get_parser_config(text))
+        .map_err(|err| error(xml_error_context(path, err)))?;
+    let metadata = package
+        .get_child("metadata")
+        .ok_or_else(|| missing("metadata"))?;
+    let manifest = package
+        .get_child("manifest")
+        .ok_or_else(|| missing("manifest"))?;
+    let spine = package.get_child("spine").ok_or_else(|| missing("spine"))?;
+    let cover_id = parse_cover_meta(metadata);
+    let guide_cover_href = package.get_child("guide").and_then(parse_guide_cover);
+    let metadata = parse_metadata(
+        metadata,
+        package
+            .attributes
+            .get("unique-identifier")
+            .map(String::as_str),
+    );
+    if metadata.titles.is_empty() {
+        return Err(missing("title"));
+    }
+    if metadata.languages.is_empty() {
+        return Err(missing("language"));
+    }
+    let (manifest, warnings) = parse_manifest(manifest);
+    let toc_id = spine.attributes.get("toc").cloned();
+    let spine = parse_spine(spine, path)?;
+    Ok(ContentOPF {
+        path: path.to_string(),
+        metadata,
         manifest,
         spine,
+        toc_id,
+        cover_id,
+        guide_cover_href,
+        warnings,
     })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::types::EncryptionScheme;
 
     static EPUB_PAID_OFF: &[u8] = include_bytes!("../../test_resources/paid_off.epub");
     static EPUB_SHAKESPEARES: &[u8] = include_bytes!("../../test_resources/shakespeares.epub");
     static EPUB_SIMPLE: &[u8] = include_bytes!("../../test_resources/simple.epub");
     static EPUB_NESTED: &[u8] = include_bytes!("../../test_resources/nested.epub");
     static EPUB_KANJIAN: &[u8] = include_bytes!("../../test_resources/kanjian.epub");
+    static EPUB_NESTED_EMPTY_NAV: &[u8] =
+        include_bytes!("../../test_resources/nested_empty_nav.epub");
+    static EPUB_NESTED_NAV_ONLY: &[u8] =
+        include_bytes!("../../test_resources/nested_nav_only.epub");
+    static EPUB_NESTED_NO_TOC: &[u8] = include_bytes!("../../test_resources/nested_no_toc.epub");
+    static EPUB_NESTED_MISSING_CHAPTER: &[u8] =
+        include_bytes!("../../test_resources/nested_missing_chapter.epub");
+    static EPUB_NESTED_COVER: &[u8] = include_bytes!("../../test_resources/nested_cover.epub");
+    static EPUB_NESTED_COVER_FALLBACKS: &[u8] =
+        include_bytes!("../../test_resources/nested_cover_fallbacks.epub");
+    static EPUB_NESTED_ADEPT: &[u8] = include_bytes!("../../test_resources/nested_adept.epub");
+    static EPUB_NESTED_ADEPT_STYLES: &[u8] =
+        include_bytes!("../../test_resources/nested_adept_styles.epub");
+    static EPUB_NESTED_FONTS: &[u8] = include_bytes!("../../test_resources/nested_fonts.epub");
+    static EPUB_HREFS: &[u8] = include_bytes!("../../test_resources/hrefs.epub");
+    static EPUB_RENDITIONS: &[u8] = include_bytes!("../../test_resources/renditions.epub");
+    static EPUB_SIMPLE_INVALID_HTML: &[u8] =
+        include_bytes!("../../test_resources/simple-invalid_html.epub");
 
     #[test]
     fn epub_to_contentopf() {
         let epub_archive = EpubArchive::new(EPUB_PAID_OFF).unwrap();
         let content_opf = epub_archive.content_opf;
-        assert_eq!("Paid Off", &content_opf.title);
-        assert_eq!("Walter J. Coburn", &content_opf.author.unwrap());
-        assert_eq!("en", &content_opf.language);
+        assert_eq!("Paid Off", content_opf.title());
+        assert_eq!(Some("Walter J. Coburn"), content_opf.author());
+        assert_eq!("en", content_opf.language());
         assert!(!content_opf.manifest.is_empty());
         assert!(!content_opf.spine.is_empty());
     }
 
+    #[test]
+    fn epub_to_metadata() {
+        let epub_archive = EpubArchive::new(EPUB_PAID_OFF).unwrap();
+        let metadata = epub_archive.metadata();
+        assert_eq!(
+            vec![Identifier {
+                id: Some("id".to_string()),
+                scheme: Some("URI".to_string()),
+                value: "http://www.gutenberg.org/64757".to_string(),
+            }],
+            metadata.identifiers
+        );
+        assert_eq!(
+            Some("http://www.gutenberg.org/64757"),
+            metadata.unique_identifier.as_deref()
+        );
+        assert_eq!(vec!["Paid Off"], metadata.titles);
+        assert_eq!(
+            vec![Contributor {
+                name: "Walter J. Coburn".to_string(),
+                roles: vec![],
+                file_as: Some("Coburn, Walter J.".to_string()),
+                display_seq: None,
+            }],
+            metadata.creators
+        );
+        assert_eq!(Some("2021-03-08"), metadata.publication_date());
+        assert_eq!(2, metadata.dates.len());
+        assert_eq!(
+            Some("Public domain in the USA."),
+            metadata.rights.as_deref()
+        );
+        assert_eq!(
+            Some("https://www.gutenberg.org/files/64757/64757-h/64757-h.htm"),
+            metadata.source.as_deref()
+        );
+        assert_eq!(vec!["en"], metadata.languages);
+        assert_eq!(None, metadata.isbn());
+
+        let epub_archive = EpubArchive::new(EPUB_KANJIAN).unwrap();
+        let metadata = epub_archive.metadata();
+        assert_eq!("柴静", metadata.creators[0].name);
+        assert_eq!(
+            "爱下电子书 [http://www.ixdzs.com]",
+            metadata.contributors[0].name
+        );
+        assert_eq!(Some("ixdzs.com"), metadata.publisher.as_deref());
+        assert_eq!(Some("看见"), metadata.description.as_deref());
+        assert_eq!(vec!["爱下电子书"], metadata.subjects);
+        assert_eq!(vec!["zh-cn"], metadata.languages);
+        // empty dates are skipped
+        assert!(metadata.dates.is_empty());
+
+        let book = EpubArchive::new(EPUB_SIMPLE).unwrap().to_book().unwrap();
+        assert_eq!(
+            vec![
+                Date {
+                    event: None,
+                    value: "2021-05-02T21:47:20Z".to_string()
+                },
+                Date {
+                    event: Some("modification".to_string()),
+                    value: "2021-05-02T21:47:20Z".to_string()
+                }
+            ],
+            book.metadata.dates
+        );
+    }
+
+    #[test]
+    fn parse_contributor_roles() {
+        let metadata_xml = r#"<metadata xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf">
+  <dc:creator opf:role="trl" opf:file-as="Doe, Jane">Jane Doe</dc:creator>
+  <dc:creator opf:role="aut">Pu Songling</dc:creator>
+  <dc:contributor opf:role="edt">Editor</dc:contributor>
+</metadata>"#;
+        let metadata = parse_metadata(&Element::parse(metadata_xml.as_bytes()).unwrap(), None);
+        assert_eq!(vec!["trl"], metadata.creators[0].roles);
+        assert_eq!(Some("Doe, Jane"), metadata.creators[0].file_as.as_deref());
+        assert!(metadata.creators[1].has_role("aut"));
+        assert!(metadata.contributors[0].has_role("edt"));
+
+        let metadata_xml = r##"<metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+  <dc:creator id="trl">Jane Doe</dc:creator>
+  <meta refines="#trl" property="role" scheme="marc:relators">trl</meta>
+  <meta refines="#trl" property="file-as">Doe, Jane</meta>
+  <meta refines="#trl" property="display-seq">2</meta>
+  <dc:creator id="aut">Pu Songling</dc:creator>
+  <meta refines="#aut" property="role" scheme="marc:relators">aut</meta>
+  <meta refines="#aut" property="display-seq">1</meta>
+  <dc:creator>Anonymous</dc:creator>
+</metadata>"##;
+        let metadata = parse_metadata(&Element::parse(metadata_xml.as_bytes()).unwrap(), None);
+        assert_eq!(
+            vec![
+                Contributor {
+                    name: "Pu Songling".to_string(),
+                    roles: vec!["aut".to_string()],
+                    file_as: None,
+                    display_seq: Some(1),
+                },
+                Contributor {
+                    name: "Jane Doe".to_string(),
+                    roles: vec!["trl".to_string()],
+                    file_as: Some("Doe, Jane".to_string()),
+                    display_seq: Some(2),
+                },
+                Contributor {
+                    name: "Anonymous".to_string(),
+                    roles: vec![],
+                    file_as: None,
+                    display_seq: None,
+                },
+            ],
+            metadata.creators
+        );
+    }
+
+    #[test]
+    fn parse_manifest_skips_incomplete_items() {
+        let manifest_xml = r#"<manifest>
+  <item id="ch1" href="ch1.xhtml" media-type="application/xhtml+xml" />
+  <item id="ch2" media-type="application/xhtml+xml" />
+  <item href="image.png" media-type="image/png" />
+</manifest>"#;
+        let (manifest, warnings) =
+            parse_manifest(&Element::parse(manifest_xml.as_bytes()).unwrap());
+        assert_eq!(1, manifest.len());
+        assert_eq!(
+            vec![
+                ParseWarning::SkippedManifestItem {
+                    id: "ch2".to_string(),
+                    attribute: "href".to_string()
+                },
+                ParseWarning::SkippedManifestItem {
+                    id: "image.png".to_string(),
+                    attribute: "id".to_string()
+                },
+            ],
+            warnings
+        );
+    }
+
+    #[test]
+    fn parse_epub3_identifier_type() {
+        let metadata_xml = r##"<metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+  <dc:identifier id="uid">urn:uuid:c5970b17-dfcf-475c-a2de-f0fee6973a4b</dc:identifier>
+  <dc:identifier id="isbn">9780000000002</dc:identifier>
+  <meta refines="#isbn" property="identifier-type" scheme="onix:codelist5">15</meta>
+</metadata>"##;
+        let metadata = parse_metadata(
+            &Element::parse(metadata_xml.as_bytes()).unwrap(),
+            Some("uid"),
+        );
+        assert_eq!(Some("9780000000002"), metadata.isbn());
+        assert_eq!(
+            Some("urn:uuid:c5970b17-dfcf-475c-a2de-f0fee6973a4b"),
+            metadata.unique_identifier.as_deref()
+        );
+        let metadata_xml = r#"<metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+  <dc:identifier>urn:isbn:9780000000002</dc:identifier>
+</metadata>"#;
+        let metadata = parse_metadata(&Element::parse(metadata_xml.as_bytes()).unwrap(), None);
+        assert_eq!(Some("9780000000002"), metadata.isbn());
+        assert_eq!(None, metadata.unique_identifier);
+    }
+
     #[test]
     fn epub_to_flat_ncx() {
         let epub_archive = EpubArchive::new(EPUB_PAID_OFF).unwrap();
@@ -532,12 +1864,42 @@ mod tests {
         );
     }
 
+    #[test]
+    fn epub_to_nested_nav_doc() {
+        let epub_archive = EpubArchive::new(EPUB_NESTED).unwrap();
+        assert_eq!(3, epub_archive.toc_depth());
+        let navigation = epub_archive.navigation;
+        assert_eq!(3, navigation.depth);
+        assert_eq!(3, navigation.nav_points.len());
+        let chapter_1_1_2 = &navigation.nav_points[0].children[0].children[1];
+        assert_eq!("Chapter 1.1.2", chapter_1_1_2.label.as_ref().unwrap());
+        assert_eq!("text/ch001.xhtml#chapter-1.1.2", chapter_1_1_2.src);
+        assert_eq!("toc-li-4", chapter_1_1_2.id);
+        assert_eq!(Some(4), chapter_1_1_2.play_order);
+        assert_eq!(3, chapter_1_1_2.level);
+    }
+
+    #[test]
+    fn nav_doc_unlinked_heading() {
+        let nav_doc = r##"<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+<body><nav epub:type="landmarks"><ol><li><a href="cover.xhtml">Cover</a></li></ol></nav>
+<nav epub:type="toc"><ol>
+<li><span>Part <em>One</em></span><ol><li><a href="ch1.xhtml">Chapter 1</a></li></ol></li>
+</ol></nav></body></html>"##;
+        let navigation = parse_nav_doc(nav_doc, "nav.xhtml").unwrap();
+        assert_eq!(2, navigation.depth);
+        let part = &navigation.nav_points[0];
+        assert_eq!("Part One", part.label.as_ref().unwrap());
+        assert_eq!("ch1.xhtml", part.src);
+        assert_eq!("navpoint-1", part.id);
+        assert_eq!("ch1.xhtml", part.children[0].src);
+    }
+
     #[test]
     fn simple_epub_to_book() {
         let expected_author = "蒲松龄";
         let expected_title = "聊斋志异白话文";
         let expected_chapter_titles = vec![
-            "聊斋志异白话文",
             "卷一 考城隍",
             "卷一 耳中人",
             "卷一 尸变",
@@ -558,25 +1920,41 @@ mod tests {
             "卷一 斫蟒",
             "卷一 犬奸",
         ];
-        let expected_chapter2_start = "卷一 考城隍 我姐夫的祖父，名叫宋焘，是本县的廪生";
-        let expected_chapter3_start = "卷一 耳中人 谭晋玄，是本县的一名秀才。";
-        let expected_chapter4_start = "卷一 尸变 阳信县某老翁";
+        let expected_preface_start = "聊斋志异白话文";
+        let expected_chapter1_start = "卷一 考城隍我姐夫的祖父，名叫宋焘，是本县的廪生";
+        let expected_chapter2_start = "卷一 耳中人谭晋玄，是本县的一名秀才。";
+        let expected_chapter3_start = "卷一 尸变阳信县某老翁";
 
         let epub_archive = EpubArchive::new(EPUB_SIMPLE).unwrap();
         let book = epub_archive
             .to_book()
             .expect("simple.epub should be parsed to book without error");
         let chapter_titles = book.chapters.iter().map(|ch| &ch.title).collect::<Vec<_>>();
+        let chapter1 = book.chapters.first().expect("Book should contain chapters");
         let chapter2 = book.chapters.get(1).expect("Book should contain chapters");
         let chapter3 = book.chapters.get(2).expect("Book should contain chapters");
-        let chapter4 = book.chapters.get(3).expect("Book should contain chapters");
         assert_eq!(Some(expected_author.to_string()), book.author);
         assert_eq!(expected_title, &book.title);
         assert_eq!(expected_chapter_titles, chapter_titles);
-        assert_eq!("卷一 考城隍", chapter2.title);
+        // the title page is not part of the navigation document
+        assert!(book.preface_content.starts_with(expected_preface_start));
+        assert_eq!("卷一 考城隍", chapter1.title);
+        assert!(chapter1.text.starts_with(expected_chapter1_start));
         assert!(chapter2.text.starts_with(expected_chapter2_start));
         assert!(chapter3.text.starts_with(expected_chapter3_start));
-        assert!(chapter4.text.starts_with(expected_chapter4_start));
+    }
+
+    #[test]
+    fn simple_invalid_html_epub_to_book() {
+        let epub_archive = EpubArchive::new(EPUB_SIMPLE_INVALID_HTML).unwrap();
+        let book = epub_archive
+            .to_book()
+            .expect("simple-invalid_html.epub should be parsed to book without error");
+        let chapter1 = book.chapters.first().expect("Book should contain chapters");
+        assert_eq!("卷一 考城隍", chapter1.title);
+        assert!(chapter1
+            .text
+            .contains("卷一 考城隍我姐夫的祖父，名叫宋焘，是本县的廪生"));
     }
 
     #[test]
@@ -584,26 +1962,29 @@ mod tests {
         let expected_author = "Jannes".to_string();
         let expected_title = "Nested example".to_string();
         let expected_chapters = vec![
-            Chapter {
-                title: "Nested example".to_string(),
-                text: "Nested example Nested example Jannes".to_string(),
-                subchapters: vec![],
-            },
             Chapter {
                 title: "Chapter 1".to_string(),
+                blocks: None,
+                notes: vec![],
                 text: "Chapter 1 This is Chapter 1".to_string(),
                 subchapters: vec![
                     Chapter {
                         title: "Chapter 1.1".to_string(),
+                        blocks: None,
+                        notes: vec![],
                         text: "Chapter 1.1 This is Chapter 1.1".to_string(),
                         subchapters: vec![
                             Chapter {
                                 title: "Chapter 1.1.1".to_string(),
+                                blocks: None,
+                                notes: vec![],
                                 text: "Chapter 1.1.1 This is Chapter 1.1.1".to_string(),
                                 subchapters: vec![],
                             },
                             Chapter {
                                 title: "Chapter 1.1.2".to_string(),
+                                blocks: None,
+                                notes: vec![],
                                 text: "Chapter 1.1.2 This is Chapter 1.1.2".to_string(),
                                 subchapters: vec![],
                             },
@@ -611,6 +1992,8 @@ mod tests {
                     },
                     Chapter {
                         title: "Chapter 1.2".to_string(),
+                        blocks: None,
+                        notes: vec![],
                         text: "Chapter 1.2 This is Chapter 1.2".to_string(),
                         subchapters: vec![],
                     },
@@ -618,12 +2001,18 @@ mod tests {
             },
             Chapter {
                 title: "Chapter 2".to_string(),
+                blocks: None,
+                notes: vec![],
                 text: "Chapter 2 This is Chapter 2".to_string(),
                 subchapters: vec![Chapter {
                     title: "Chapter 2.1".to_string(),
+                    blocks: None,
+                    notes: vec![],
                     text: "Chapter 2.1".to_string(),
                     subchapters: vec![Chapter {
                         title: "Chapter 2.1.1".to_string(),
+                        blocks: None,
+                        notes: vec![],
                         text: "Chapter 2.1.1 This is Chapter 2.1.1".to_string(),
                         subchapters: vec![],
                     }],
@@ -631,30 +2020,435 @@ mod tests {
             },
             Chapter {
                 title: "Chapter 3".to_string(),
+                blocks: None,
+                notes: vec![],
                 text: "Chapter 3".to_string(),
                 subchapters: vec![Chapter {
                     title: "Chapter 3.1".to_string(),
+                    blocks: None,
+                    notes: vec![],
                     text: "Chapter 3.1".to_string(),
                     subchapters: vec![Chapter {
                         title: "Chapter 3.1.1".to_string(),
+                        blocks: None,
+                        notes: vec![],
                         text: "Chapter 3.1.1 This is Chapter 3.1.1".to_string(),
                         subchapters: vec![],
                     }],
                 }],
             },
         ];
+        let epub_archive = EpubArchive::new(EPUB_NESTED).unwrap();
         let expected_book = Book {
             title: expected_title,
             author: Some(expected_author),
-            preface_content: "".to_string(),
+            metadata: epub_archive.metadata().clone(),
+            // the title page is not part of the navigation document
+            preface_content: "Nested example Jannes".to_string(),
+            preface_blocks: None,
+            preface_notes: vec![],
             chapters: expected_chapters,
         };
 
-        let epub_archive = EpubArchive::new(EPUB_NESTED).unwrap();
         let book = epub_archive
             .to_book()
             .expect("nested.epub should be parsed to book without error");
         assert_eq!(expected_book, book);
+
+        // Epub 3 only version without toc.ncx is parsed the same way
+        let epub_archive = EpubArchive::new(EPUB_NESTED_NAV_ONLY).unwrap();
+        let book = epub_archive
+            .to_book()
+            .expect("nested_nav_only.epub should be parsed to book without error");
+        assert_eq!(expected_book, book);
+    }
+
+    #[test]
+    fn parts_are_kept_while_needed() {
+        let epub_archive = EpubArchive::new(EPUB_NESTED).unwrap();
+        epub_archive.to_book().unwrap();
+        assert!(epub_archive.parts.borrow().contents.is_empty());
+
+        // the preface shares its source with the first chapter
+        let mut layout = epub_archive.content_layout().unwrap();
+        let options = ParseOptions::default();
+        let source = layout.nav_points[&epub_archive.navigation.nav_points[0]][0].clone();
+        layout.preface.push(source.clone());
+        *layout.uses.get_mut(&source).unwrap() += 1;
+        let first = epub_archive
+            .src_to_content(&source, &layout, &options)
+            .unwrap();
+        assert!(epub_archive.parts.borrow().contents.contains_key(&source));
+        let second = epub_archive
+            .src_to_content(&source, &layout, &options)
+            .unwrap();
+        assert!(!epub_archive.parts.borrow().contents.contains_key(&source));
+        assert_eq!(first.text, second.text);
+        assert_eq!(first.text, "Chapter 1 This is Chapter 1");
+    }
+
+    #[test]
+    fn empty_nav_doc_falls_back_to_ncx() {
+        let epub_archive = EpubArchive::new(EPUB_NESTED_EMPTY_NAV).unwrap();
+        assert_eq!("EPUB/toc.ncx", epub_archive.navigation.path);
+        assert_eq!(
+            vec![ParseWarning::UnusableNavDoc {
+                path: "EPUB/nav.xhtml".to_string(),
+                reason: "Malformatted navigation document: `EPUB/nav.xhtml`: Empty table of contents, element `ol`".to_string(),
+            }],
+            epub_archive.warnings()
+        );
+        let book = epub_archive.to_book().unwrap();
+        let chapter_titles = book.chapters.iter().map(|ch| &ch.title).collect::<Vec<_>>();
+        assert_eq!(
+            vec!["Nested example", "Chapter 1", "Chapter 2", "Chapter 3"],
+            chapter_titles
+        );
+        assert_eq!("Chapter 1.1", book.chapters[1].subchapters[0].title);
+    }
+
+    #[test]
+    fn nested_no_toc_epub_to_book() {
+        let epub_archive = EpubArchive::new(EPUB_NESTED_NO_TOC).unwrap();
+        let book = epub_archive
+            .to_book()
+            .expect("nested_no_toc.epub should be parsed to book without error");
+        let chapter_titles = book.chapters.iter().map(|ch| &ch.title).collect::<Vec<_>>();
+        assert_eq!("", &book.preface_content);
+        assert_eq!(
+            vec!["Nested example", "Chapter 1", "Chapter 2", "Chapter 3"],
+            chapter_titles
+        );
+        assert!(book.chapters.iter().all(|ch| ch.subchapters.is_empty()));
+        assert_eq!("Nested example Jannes", book.chapters[0].text);
+        assert!(book.chapters[2].text.ends_with(
+            "Chapter 2 This is Chapter 2 Chapter 2.1 Chapter 2.1.1 This is Chapter 2.1.1"
+        ));
+    }
+
+    #[test]
+    fn toc_hrefs_matched_exactly() {
+        // toc.ncx is in a subdirectory of the package document,
+        // the hrefs are percent-encoded and `1.xhtml` is contained in `11.xhtml`
+        let epub_archive = EpubArchive::new(EPUB_HREFS).unwrap();
+        let book = epub_archive.to_book().unwrap();
+        let chapters = book
+            .chapters
+            .iter()
+            .map(|ch| (ch.title.as_str(), ch.text.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                ("Chapter 1", "Chapter 1 This is Chapter 1"),
+                ("Chapter 2", "Chapter 2 This is Chapter 2"),
+                ("Chapter 2 b", "Part b This is part b"),
+                ("Chapter 11", "Chapter 11 This is Chapter 11"),
+            ],
+            chapters
+        );
+        assert!(epub_archive.warnings().is_empty());
+        assert!(epub_archive.validate().is_empty());
+    }
+
+    #[test]
+    fn chapters_loaded_on_demand() {
+        // the content document of chapter 3 is missing from the archive
+        let epub_archive = EpubArchive::new(EPUB_NESTED_MISSING_CHAPTER).unwrap();
+        assert_eq!("Nested example", epub_archive.title());
+        assert_eq!(Some("Jannes"), epub_archive.author());
+        let toc_titles = epub_archive
+            .toc()
+            .iter()
+            .map(|entry| entry.title.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["Chapter 1", "Chapter 2", "Chapter 3"], toc_titles);
+        assert_eq!(
+            "Chapter 1.1.2",
+            epub_archive.toc()[0].subentries[0].subentries[1].title
+        );
+
+        let options = ParseOptions::default();
+        let mut chapters = epub_archive.chapters(&options).unwrap();
+        let chapter1 = chapters.next().unwrap().unwrap();
+        assert_eq!("Chapter 1 This is Chapter 1", chapter1.text);
+        assert_eq!(2, chapter1.subchapters.len());
+        assert!(chapters.next().unwrap().is_ok());
+        let err = chapters.next().unwrap().err().unwrap();
+        assert!(err.to_string().contains("`EPUB/text/ch003.xhtml`"));
+        let json = serde_json::to_value(&err).unwrap();
+        assert_eq!("ZipError", json["type"]);
+        assert_eq!("EPUB/text/ch003.xhtml", json["path"]);
+        assert!(chapters.next().is_none());
+        assert!(epub_archive.to_book().is_err());
+
+        let expected_book = EpubArchive::new(EPUB_NESTED).unwrap().to_book().unwrap();
+        let epub_archive = EpubArchive::new(EPUB_NESTED).unwrap();
+        assert_eq!(
+            expected_book.preface_content,
+            epub_archive.preface(&options).unwrap().text
+        );
+        let chapters = epub_archive
+            .chapters(&options)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(expected_book.chapters, chapters);
+    }
+
+    #[test]
+    fn epub_cover() {
+        // referenced by <meta name="cover">
+        let epub_archive = EpubArchive::new(EPUB_PAID_OFF).unwrap();
+        let cover = epub_archive
+            .cover()
+            .unwrap()
+            .expect("paid_off.epub has a cover");
+        assert_eq!(
+            "OEBPS/@public@vhost@g@gutenberg@html@files@64757@64757-h@images@cover.jpg",
+            cover.path
+        );
+        assert_eq!("image/jpeg", cover.media_type);
+        assert_eq!(&[0xFF, 0xD8], &cover.data[..2]);
+
+        // first image of the first spine item
+        let epub_archive = EpubArchive::new(EPUB_NESTED_COVER).unwrap();
+        let cover = epub_archive
+            .cover()
+            .unwrap()
+            .expect("nested_cover.epub has a cover");
+        assert_eq!("EPUB/media/cover.png", cover.path);
+        assert_eq!("image/png", cover.media_type);
+        assert_eq!(b"\x89PNG", &cover.data[..4]);
+
+        let epub_archive = EpubArchive::new(EPUB_NESTED).unwrap();
+        assert_eq!(None, epub_archive.cover().unwrap());
+
+        // the guide references a missing page, the first spine item has an embedded image
+        let epub_archive = EpubArchive::new(EPUB_NESTED_COVER_FALLBACKS).unwrap();
+        assert_eq!(None, epub_archive.cover().unwrap());
+    }
+
+    #[test]
+    fn epub_resources() {
+        let epub_archive = EpubArchive::new(EPUB_NESTED_COVER).unwrap();
+        let items = epub_archive.manifest_items();
+        assert_eq!(8, items.len());
+        let images = items
+            .iter()
+            .filter(|item| item.media_type().starts_with("image/"))
+            .collect::<Vec<_>>();
+        assert_eq!(1, images.len());
+        assert_eq!("cover_png", images[0].id());
+        assert_eq!("media/cover.png", images[0].href());
+        let image = epub_archive.read_item(images[0]).unwrap();
+        assert_eq!("EPUB/media/cover.png", image.path);
+        assert_eq!("image/png", image.media_type);
+
+        let nav_item = epub_archive.manifest_item("nav").unwrap();
+        assert_eq!(vec!["nav"], nav_item.properties().collect::<Vec<_>>());
+        let stylesheet_path = epub_archive.resolve_href("styles/../styles/stylesheet1.css");
+        assert_eq!("EPUB/styles/stylesheet1.css", stylesheet_path);
+        let stylesheet = epub_archive.read_file(&stylesheet_path).unwrap();
+        assert_eq!("text/css", stylesheet.media_type);
+        assert!(!stylesheet.data.is_empty());
+        let err = epub_archive.read_file("EPUB/missing.png").err().unwrap();
+        assert!(matches!(
+            &err,
+            ParseError::ZipError { path: Some(path), .. } if path == "EPUB/missing.png"
+        ));
+        assert_eq!(
+            "Error in underlying Zip archive at `EPUB/missing.png`: specified file not found in archive",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn full_path_normalized() {
+        assert_eq!(
+            "OEBPS/text/ch1.xhtml",
+            get_full_path(Path::new("OEBPS"), "text/ch1.xhtml")
+        );
+        assert_eq!(
+            "OEBPS/images/cover.jpg",
+            get_full_path(Path::new("OEBPS/text"), "../images/./cover.jpg")
+        );
+        assert_eq!("toc.ncx", get_full_path(Path::new(""), "toc.ncx"));
+        assert_eq!(
+            "OEBPS/chapter 2.xhtml",
+            get_full_path(Path::new("OEBPS/toc"), "../chapter%202.xhtml")
+        );
+    }
+
+    #[test]
+    fn parse_ncx_empty_nav_map() {
+        let ncx = r#"<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+<head><meta name="dtb:depth" content="1"/></head>
+<docTitle><text>Empty</text></docTitle>
+<navMap></navMap>
+</ncx>"#;
+        assert!(parse_ncx(ncx, "toc.ncx").unwrap().nav_points.is_empty());
+    }
+
+    #[test]
+    fn drm_protected_epub() {
+        match EpubArchive::new(EPUB_NESTED_ADEPT) {
+            Err(ParseError::DrmProtected { scheme, path }) => {
+                assert_eq!(EncryptionScheme::AdobeAdept, scheme);
+                assert_eq!("EPUB/text/ch001.xhtml", path);
+            }
+            _ => panic!("expected DRM error"),
+        }
+
+        // only the stylesheet is encrypted
+        let epub_archive = EpubArchive::new(EPUB_NESTED_ADEPT_STYLES).unwrap();
+        assert_eq!(
+            vec![ParseWarning::EncryptedResource {
+                path: "EPUB/styles/stylesheet1.css".to_string(),
+                scheme: EncryptionScheme::AdobeAdept,
+            }],
+            epub_archive.warnings()
+        );
+        assert!(epub_archive.to_book().is_ok());
+    }
+
+    #[test]
+    fn obfuscated_fonts() {
+        let epub_archive = EpubArchive::new(EPUB_NESTED_FONTS).unwrap();
+        let font: Vec<u8> = (0..2000).map(|i| (i % 251) as u8).collect();
+        for id in &["font_idpf", "font_adobe"] {
+            let item = epub_archive.manifest_item(id).unwrap();
+            assert_eq!(font, epub_archive.read_item(item).unwrap().data);
+        }
+        assert_eq!(
+            font,
+            epub_archive.read_file("EPUB/fonts/idpf.otf").unwrap().data
+        );
+        assert!(epub_archive.warnings().is_empty());
+    }
+
+    #[test]
+    fn multiple_renditions() {
+        let epub_archive = EpubArchive::new(EPUB_RENDITIONS).unwrap();
+        // the pdf rootfile is skipped
+        assert_eq!("Nested example", epub_archive.title());
+        let rootfiles = epub_archive.rootfiles();
+        assert_eq!(3, rootfiles.len());
+        assert_eq!("print/book.pdf", rootfiles[0].full_path);
+        assert_eq!("application/pdf", rootfiles[0].media_type);
+        assert_eq!(None, rootfiles[0].layout);
+        assert_eq!(Some("Reflowable"), rootfiles[1].label.as_deref());
+        assert_eq!(
+            Rootfile {
+                full_path: "OEBPS/content.opf".to_string(),
+                media_type: "application/oebps-package+xml".to_string(),
+                label: Some("Fixed layout".to_string()),
+                layout: Some("pre-paginated".to_string()),
+                language: None,
+                media: Some("(orientation: landscape)".to_string()),
+                access_mode: Some("visual".to_string()),
+            },
+            rootfiles[2]
+        );
+
+        let epub_archive = EpubArchive::new_with_rendition(EPUB_RENDITIONS, 2).unwrap();
+        assert_eq!("Hrefs example", epub_archive.title());
+        assert_eq!(4, epub_archive.toc().len());
+        assert!(EpubArchive::new_with_rendition(EPUB_RENDITIONS, 3).is_err());
+
+        let err = EpubArchive::new_with_rendition(EPUB_RENDITIONS, 0)
+            .err()
+            .unwrap();
+        assert_eq!(
+            "Malformatted/missing container.xml file: `META-INF/container.xml`: Rendition 0 is not a package document, media type `application/pdf`, element `rootfile`",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn parse_container_xml() {
+        let container = r#"<container xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+<rootfiles><rootfile media-type='application/oebps-package+xml' full-path='OEBPS/my book.opf'/></rootfiles>
+</container>"#;
+        let rootfiles = parse_container(container).unwrap();
+        assert_eq!("OEBPS/my book.opf", rootfiles[0].full_path);
+
+        let err = parse_container("<container><rootfiles/></container>")
+            .err()
+            .unwrap();
+        assert_eq!(
+            "Malformatted/missing container.xml file: `META-INF/container.xml`: No package document given, element `rootfile`",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn ncx_item_from_spine_or_media_type() {
+        let opf = |spine_toc: &str| {
+            format!(
+                r#"<package xmlns="http://www.idpf.org/2007/opf" version="2.0">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:title>Title</dc:title><dc:language>en</dc:language>
+  </metadata>
+  <manifest>
+    <item id="toc" href="toc.xhtml" media-type="application/xhtml+xml"/>
+    <item id="ncxtoc" href="nav/toc.ncx" media-type="application/x-dtbncx+xml"/>
+    <item id="old_ncx" href="old.ncx" media-type="application/xml"/>
+  </manifest>
+  <spine{}><itemref idref="toc"/></spine>
+</package>"#,
+                spine_toc
+            )
+        };
+        let ncx_href = |text: &str| {
+            let content_opf = parse_content_opf(text, "OEBPS/content.opf").ok().unwrap();
+            content_opf.get_ncx_item().map(|item| item.href.clone())
+        };
+        assert_eq!(
+            Some("old.ncx".to_string()),
+            ncx_href(&opf(r#" toc="old_ncx""#))
+        );
+        assert_eq!(Some("nav/toc.ncx".to_string()), ncx_href(&opf("")));
+        assert_eq!(
+            Some("nav/toc.ncx".to_string()),
+            ncx_href(&opf(r#" toc="missing""#))
+        );
+    }
+
+    #[test]
+    fn parse_errors_with_context() {
+        let opf = r#"<?xml version="1.0"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="2.0">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:title>Title</dc:title></metadata>
+  <manifest/>
+  <spine/>
+</package>"#;
+        let err = parse_content_opf(opf, "OEBPS/content.opf").err().unwrap();
+        let context = err.context();
+        assert_eq!("OEBPS/content.opf", context.path);
+        assert_eq!(Some("language"), context.element.as_deref());
+        assert_eq!(
+            "Malformatted content.opf file: `OEBPS/content.opf`: Missing language, element `language`",
+            err.to_string()
+        );
+
+        let err = parse_content_opf("<package>\n<metadata>\n</package>", "content.opf")
+            .err()
+            .unwrap();
+        let context = err.context();
+        assert_eq!(Some(3), context.line);
+        assert!(context.column.is_some());
+
+        let ncx = r#"<ncx><head><meta name="dtb:depth" content="1"/></head>
+<navMap><navPoint id="np1"><navLabel><text>One</text></navLabel></navPoint></navMap></ncx>"#;
+        let err = parse_ncx(ncx, "toc.ncx").err().unwrap();
+        let context = err.context();
+        assert_eq!(Some("content"), context.element.as_deref());
+        assert_eq!(Some("src"), context.attribute.as_deref());
+
+        let json = serde_json::to_value(ParseError::from(err)).unwrap();
+        assert_eq!("EpubError", json["type"]);
+        assert_eq!("MalformattedTocNcx", json["error"]["type"]);
+        assert_eq!("toc.ncx", json["error"]["path"]);
     }
 
     #[test]
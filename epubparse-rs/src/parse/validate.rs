@@ -0,0 +1,271 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Seek};
+
+use zip::CompressionMethod;
+
+use super::{get_full_path, EpubArchive};
+use crate::errors::ParseWarning;
+use crate::types::{Finding, Severity};
+use crate::util;
+
+const MIMETYPE: &str = "application/epub+zip";
+
+impl<R: Read + Seek> EpubArchive<R> {
+    /// Check the epub file against the core rules of the container, package document
+    /// and table of contents, also reporting the warnings of opening the archive
+    ///
+    /// All content documents referenced by the table of contents are read,
+    /// the result does not depend on the content extracted before
+    pub fn validate(&self) -> Vec<Finding> {
+        let mut findings = Vec::new();
+        self.validate_mimetype(&mut findings);
+        self.validate_metadata(&mut findings);
+        self.validate_manifest(&mut findings);
+        let invalid_targets = self.validate_toc(&mut findings);
+        // warnings of extracting content are left out, as is a warning
+        // about a TOC target that was already reported as error
+        let warnings = self.warnings().into_iter().filter(|warning| match warning {
+            ParseWarning::UnresolvedTocTarget { target } => !invalid_targets.contains(target),
+            ParseWarning::MissingAnchor { .. }
+            | ParseWarning::DuplicateId { .. }
+            | ParseWarning::RecoveredHtml { .. }
+            | ParseWarning::ObfuscatedResource { .. } => false,
+            ParseWarning::SkippedManifestItem { .. }
+            | ParseWarning::TocFromSpine { .. }
+            | ParseWarning::UnusableNavDoc { .. }
+            | ParseWarning::EncryptedResource { .. } => true,
+        });
+        findings.extend(warnings.map(|warning| Finding {
+            severity: Severity::Warning,
+            path: warning.path().map(String::from),
+            message: warning.to_string(),
+        }));
+        findings
+    }
+
+    // the mimetype file must be the first file of the archive, stored uncompressed
+    fn validate_mimetype(&self, findings: &mut Vec<Finding>) {
+        let mut zip = self.zip.borrow_mut();
+        let mut first_file = match zip.zip_archive.by_index(0) {
+            Ok(file) => file,
+            Err(_) => {
+                findings.push(error(None, "Archive is empty"));
+                return;
+            }
+        };
+        if first_file.name() != "mimetype" {
+            findings.push(error(
+                Some(first_file.name()),
+                "The mimetype file is not the first file of the archive",
+            ));
+            return;
+        }
+        if first_file.compression() != CompressionMethod::Stored {
+            findings.push(error(Some("mimetype"), "The mimetype file is compressed"));
+        }
+        let mut mimetype = String::new();
+        if first_file.read_to_string(&mut mimetype).is_err() || mimetype != MIMETYPE {
+            findings.push(error(
+                Some("mimetype"),
+                &format!("The mimetype file does not contain `{}`", MIMETYPE),
+            ));
+        }
+    }
+
+    fn validate_metadata(&self, findings: &mut Vec<Finding>) {
+        let path = Some(self.content_opf.path.as_str());
+        let metadata = &self.content_opf.metadata;
+        if metadata.identifiers.is_empty() {
+            findings.push(error(path, "Missing dc:identifier"));
+        } else if metadata.unique_identifier.is_none() {
+            findings.push(error(
+                path,
+                "The unique-identifier of the package does not reference a dc:identifier",
+            ));
+        }
+        if metadata.titles.iter().all(|title| title.trim().is_empty()) {
+            findings.push(error(path, "Empty dc:title"));
+        }
+        if metadata
+            .languages
+            .iter()
+            .all(|language| language.trim().is_empty())
+        {
+            findings.push(error(path, "Empty dc:language"));
+        }
+    }
+
+    fn validate_manifest(&self, findings: &mut Vec<Finding>) {
+        let path = Some(self.content_opf.path.as_str());
+        for item_id in &self.content_opf.spine {
+            if !self.content_opf.manifest.contains_key(item_id) {
+                findings.push(error(
+                    path,
+                    &format!("Spine item `{}` is not in the manifest", item_id),
+                ));
+            }
+        }
+        let zip = self.zip.borrow();
+        for item in self.manifest_items() {
+            // remote resources are not part of the archive
+            if item.href.contains("://") {
+                continue;
+            }
+            let item_path = get_full_path(&self.content_opf_dir, &item.href);
+            if !zip.has_file(&item_path) {
+                findings.push(error(
+                    path,
+                    &format!(
+                        "Manifest item `{}` is missing from the archive: `{}`",
+                        item.id, item_path
+                    ),
+                ));
+            }
+        }
+    }
+
+    // every nav point must point to a content document in the manifest,
+    // and its anchor must exist in that document
+    //
+    // returns the targets of the nav points with errors
+    fn validate_toc(&self, findings: &mut Vec<Finding>) -> HashSet<String> {
+        let path = Some(self.navigation.path.as_str());
+        let mut invalid_targets = HashSet::new();
+        let mut anchors_by_file: HashMap<String, Option<HashSet<String>>> = HashMap::new();
+        for nav_point in self.navigation.get_flattened_nav_points() {
+            let source = self.resolve_src(&nav_point.src);
+            let in_manifest = self
+                .content_opf
+                .manifest
+                .values()
+                .any(|item| self.resolve_href(&item.href) == source.path);
+            if !in_manifest {
+                findings.push(error(
+                    path,
+                    &format!("TOC target `{}` is not in the manifest", nav_point.src),
+                ));
+                invalid_targets.insert(nav_point.src.clone());
+                continue;
+            }
+            let anchor = match &source.anchor {
+                Some(anchor) => anchor,
+                None => continue,
+            };
+            let anchors = anchors_by_file
+                .entry(source.path.clone())
+                .or_insert_with(|| self.read_anchors(&source.path));
+            if let Some(anchors) = anchors {
+                if !anchors.contains(anchor) {
+                    findings.push(error(
+                        path,
+                        &format!("TOC target `{}` has no matching anchor", nav_point.src),
+                    ));
+                    invalid_targets.insert(nav_point.src.clone());
+                }
+            }
+        }
+        invalid_targets
+    }
+
+    // ids and named anchors of a content document, none if it can not be read
+    fn read_anchors(&self, path: &str) -> Option<HashSet<String>> {
+        let text = self.zip.borrow_mut().get_file_content(path).ok()?;
+        let root = util::parse_html(&text);
+        let mut anchor_counts = HashMap::new();
+        util::count_anchors(&root, &mut anchor_counts);
+        Some(
+            anchor_counts
+                .keys()
+                .map(|anchor| anchor.to_string())
+                .collect(),
+        )
+    }
+}
+
+fn error(path: Option<&str>, message: &str) -> Finding {
+    Finding {
+        severity: Severity::Error,
+        path: path.map(String::from),
+        message: message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ParseOptions;
+
+    static EPUB_NESTED: &[u8] = include_bytes!("../../../test_resources/nested.epub");
+    static EPUB_NESTED_INVALID: &[u8] =
+        include_bytes!("../../../test_resources/nested_invalid.epub");
+    static EPUB_NESTED_ADEPT_STYLES: &[u8] =
+        include_bytes!("../../../test_resources/nested_adept_styles.epub");
+
+    #[test]
+    fn valid_epub() {
+        let epub_archive = EpubArchive::new(EPUB_NESTED).unwrap();
+        assert_eq!(Vec::<Finding>::new(), epub_archive.validate());
+    }
+
+    #[test]
+    fn invalid_epub() {
+        let epub_archive = EpubArchive::new(EPUB_NESTED_INVALID).unwrap();
+        let messages: Vec<(Severity, Option<String>, String)> = epub_archive
+            .validate()
+            .into_iter()
+            .map(|finding| (finding.severity, finding.path, finding.message))
+            .collect();
+        let error = |path: &str, message: &str| {
+            (Severity::Error, Some(path.to_string()), message.to_string())
+        };
+        assert_eq!(
+            vec![
+                error("mimetype", "The mimetype file is compressed"),
+                error(
+                    "EPUB/content.opf",
+                    "The unique-identifier of the package does not reference a dc:identifier"
+                ),
+                error(
+                    "EPUB/content.opf",
+                    "Manifest item `ch003_xhtml` is missing from the archive: `EPUB/text/ch003.xhtml`"
+                ),
+                error(
+                    "EPUB/nav.xhtml",
+                    "TOC target `text/ch002.xhtml#missing` has no matching anchor"
+                ),
+            ],
+            messages
+        );
+
+        // content warnings of extracting the readable chapters are not repeated
+        let chapters = epub_archive.chapters(&ParseOptions::default()).unwrap();
+        assert_eq!(2, chapters.take_while(Result::is_ok).count());
+        assert!(!epub_archive.warnings().is_empty());
+        assert_eq!(
+            messages,
+            epub_archive
+                .validate()
+                .into_iter()
+                .map(|finding| (finding.severity, finding.path, finding.message))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn warnings_with_path() {
+        let epub_archive = EpubArchive::new(EPUB_NESTED_ADEPT_STYLES).unwrap();
+        let warnings: Vec<Finding> = epub_archive
+            .validate()
+            .into_iter()
+            .filter(|finding| finding.severity == Severity::Warning)
+            .collect();
+        assert_eq!(
+            vec![Finding {
+                severity: Severity::Warning,
+                path: Some("EPUB/styles/stylesheet1.css".to_string()),
+                message: "Resource `EPUB/styles/stylesheet1.css` is DRM protected (Adobe ADEPT) and can not be read".to_string(),
+            }],
+            warnings
+        );
+    }
+}
@@ -5,10 +5,104 @@ use serde::{Deserialize, Serialize};
 pub struct Book {
     pub title: String,
     pub author: Option<String>,
+    #[serde(default)]
+    pub metadata: Metadata,
     pub preface_content: String,
+    /// Block structure of the preface content, only present if requested by [`ParseOptions`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preface_blocks: Option<Vec<Block>>,
+    /// Notes of the preface, only extracted if requested by [`ParseOptions`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub preface_notes: Vec<Note>,
     pub chapters: Vec<Chapter>,
 }
 
+/// Dublin Core metadata of a book, as given in the package document
+#[derive(PartialEq, Eq, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Metadata {
+    pub identifiers: Vec<Identifier>,
+    /// Value of the identifier that is referenced as the package's unique identifier
+    pub unique_identifier: Option<String>,
+    pub titles: Vec<String>,
+    /// Primary creators, ordered by their display sequence
+    pub creators: Vec<Contributor>,
+    /// Secondary contributors, ordered by their display sequence
+    pub contributors: Vec<Contributor>,
+    pub publisher: Option<String>,
+    pub dates: Vec<Date>,
+    pub description: Option<String>,
+    pub subjects: Vec<String>,
+    pub rights: Option<String>,
+    pub source: Option<String>,
+    pub languages: Vec<String>,
+}
+
+impl Metadata {
+    /// The ISBN, given either by the identifier's scheme or as urn:isbn: URN
+    pub fn isbn(&self) -> Option<&str> {
+        self.identifiers.iter().find_map(|identifier| {
+            let is_isbn_scheme = identifier
+                .scheme
+                .as_ref()
+                .map(|scheme| scheme.to_lowercase().starts_with("isbn"))
+                == Some(true);
+            if is_isbn_scheme {
+                Some(identifier.value.as_str())
+            } else {
+                identifier
+                    .value
+                    .get(..9)
+                    .filter(|prefix| prefix.eq_ignore_ascii_case("urn:isbn:"))
+                    .map(|_| &identifier.value[9..])
+            }
+        })
+    }
+
+    /// The publication date, a date without event (Epub 3) or with the publication event (Epub 2)
+    pub fn publication_date(&self) -> Option<&str> {
+        self.dates
+            .iter()
+            .find(|date| date.event.is_none() || date.event.as_deref() == Some("publication"))
+            .map(|date| date.value.as_str())
+    }
+}
+
+/// A person or organization responsible for the book
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct Contributor {
+    pub name: String,
+    /// MARC relator codes, e.g. "aut" (author), "trl" (translator) or "edt" (editor)
+    pub roles: Vec<String>,
+    /// Name for sorting, e.g. "Shakespeare, William"
+    pub file_as: Option<String>,
+    /// Position in which the contributor should be displayed (Epub 3 `display-seq`)
+    pub display_seq: Option<u32>,
+}
+
+impl Contributor {
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r.eq_ignore_ascii_case(role))
+    }
+}
+
+/// An identifier of a book, such as an ISBN, UUID or URI
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct Identifier {
+    pub id: Option<String>,
+    /// Identifier system (`opf:scheme` in Epub 2, `identifier-type` refinement in Epub 3)
+    pub scheme: Option<String>,
+    pub value: String,
+}
+
+/// A date of a book's lifecycle
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct Date {
+    /// Event of the date (`opf:event` in Epub 2, "modification" for the Epub 3
+    /// `dcterms:modified` property), the publication date of Epub 3 books has no event
+    pub event: Option<String>,
+    pub value: String,
+}
+
 /// A chapter within a book
 ///
 /// A chapter has a title and content  
@@ -19,5 +113,167 @@ pub struct Book {
 pub struct Chapter {
     pub title: String,
     pub text: String,
+    /// Block structure of the text, only present if requested by [`ParseOptions`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blocks: Option<Vec<Block>>,
+    /// Footnotes and endnotes removed from the text, only extracted if requested by [`ParseOptions`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub notes: Vec<Note>,
     pub subchapters: Vec<Chapter>,
 }
+
+/// A footnote or endnote of a chapter
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct Note {
+    /// Id of the note in its content document (empty if the note has no id)
+    pub id: String,
+    /// Id of the marker referencing the note, which back-links from the note point to
+    pub marker_id: Option<String>,
+    /// Text of the marker, e.g. "1" or "*" (empty if the note is not referenced)
+    pub marker: String,
+    /// Position of the marker in the chapter's text, counted in characters
+    /// (for notes that are not referenced, the position of the note itself)
+    pub position: usize,
+    pub text: String,
+}
+
+/// An entry of a book's table of contents, available without extracting any chapter content
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct TocEntry {
+    pub title: String,
+    pub subentries: Vec<TocEntry>,
+}
+
+/// A block-level element of a chapter's structured content
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Block {
+    Paragraph {
+        content: Vec<Inline>,
+    },
+    Heading {
+        level: u8,
+        content: Vec<Inline>,
+    },
+    /// A list, each item is made up of blocks
+    List {
+        ordered: bool,
+        items: Vec<Vec<Block>>,
+    },
+    Quote {
+        blocks: Vec<Block>,
+    },
+    /// Text with preserved whitespace
+    Preformatted {
+        text: String,
+    },
+    /// A table, given as rows of cells
+    Table {
+        rows: Vec<Vec<Vec<Inline>>>,
+    },
+}
+
+/// Inline content of a paragraph, heading or table cell
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Inline {
+    Text { text: String },
+    Emphasis { content: Vec<Inline> },
+    Strong { content: Vec<Inline> },
+    Link { href: String, content: Vec<Inline> },
+    LineBreak,
+}
+
+/// Formatting of the text of chapters
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum TextFormat {
+    /// All text on a single line, with text of different elements separated by a space
+    #[default]
+    Flat,
+    /// Blocks (paragraphs, headings, list items etc.) are separated by a blank line,
+    /// line breaks are kept as newlines and preformatted text keeps its whitespace
+    Paragraphs,
+}
+
+/// A file contained in an epub archive, e.g. an image
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct Resource {
+    /// Path within the archive
+    pub path: String,
+    pub media_type: String,
+    pub data: Vec<u8>,
+}
+
+/// A rendition of an epub file, as listed by a `<rootfile>` entry of META-INF/container.xml
+///
+/// The selection attributes are given by the Epub 3 multiple-rendition specification
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct Rootfile {
+    /// Path of the package document within the archive
+    pub full_path: String,
+    /// Media type of the package document, "application/oebps-package+xml" for epub renditions
+    pub media_type: String,
+    /// Human readable name of the rendition (`rendition:label`)
+    pub label: Option<String>,
+    /// "reflowable" or "pre-paginated" (`rendition:layout`)
+    pub layout: Option<String>,
+    /// Language of the rendition (`rendition:language`)
+    pub language: Option<String>,
+    /// Media query of the devices the rendition is intended for (`rendition:media`)
+    pub media: Option<String>,
+    /// Access modes of the rendition, e.g. "visual" or "textual" (`rendition:accessMode`)
+    pub access_mode: Option<String>,
+}
+
+/// How the resources of an epub file are encrypted
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum EncryptionScheme {
+    AdobeAdept,
+    AppleFairPlay,
+    ReadiumLcp,
+    /// Only fonts are obfuscated, which can be reversed without a key
+    FontObfuscation,
+    /// Encrypted with an unrecognized DRM scheme
+    Unknown,
+}
+
+impl std::fmt::Display for EncryptionScheme {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            EncryptionScheme::AdobeAdept => "Adobe ADEPT",
+            EncryptionScheme::AppleFairPlay => "Apple FairPlay",
+            EncryptionScheme::ReadiumLcp => "Readium LCP",
+            EncryptionScheme::FontObfuscation => "font obfuscation",
+            EncryptionScheme::Unknown => "unknown scheme",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Severity of a validation finding
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Severity {
+    /// Violation of the epub specification, reading systems may fail to open the book
+    Error,
+    /// Problem that reading systems usually recover from
+    Warning,
+}
+
+/// A problem found by validating an epub file
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct Finding {
+    pub severity: Severity,
+    /// Path of the file within the archive the problem was found in
+    pub path: Option<String>,
+    pub message: String,
+}
+
+/// Options for the epub -> book conversion
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Also extract the block structure of the content ([`Chapter::blocks`])
+    pub structured_content: bool,
+    pub text_format: TextFormat,
+    /// Remove footnotes and endnotes from the text and return them as [`Chapter::notes`]
+    pub extract_notes: bool,
+}
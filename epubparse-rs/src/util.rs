@@ -1,76 +1,653 @@
-use std::collections::VecDeque;
+use std::collections::HashMap;
 
+use crate::blocks::{blocks_to_text, is_inline_element, is_skipped_element, BlockBuilder};
+use crate::errors::ParseWarning;
 use crate::html_entities::get_named_entities;
+use crate::notes::{self, NoteElement, NoteIndex, NOTE_MARKER};
+use crate::types::{Block, Note, ParseOptions, TextFormat};
+use html5ever::tendril::TendrilSink;
+use markup5ever_rcdom::{Handle, NodeData, RcDom};
 use xmltree::ParserConfig;
 
-// TODO: fix spacing for inline tags such as <i> <b> etc.
-pub fn html_to_text(
-    full_text: &str,
-    start_anchor: Option<&str>,
-    stop_anchor: Option<&str>,
-) -> Result<String, xmltree::ParseError> {
-    let mut text: Vec<String> = Vec::new();
-    let root = xmltree::XMLNode::Element(xmltree::Element::parse_with_config(
-        full_text.as_bytes(),
-        get_parser_config(),
-    )?);
-    let mut to_visit: VecDeque<&xmltree::XMLNode> = VecDeque::new();
-    to_visit.push_back(&root);
-    // do DFS for start node, visiting all nodes before on the way
-    while let Some(start) = start_anchor {
-        if let Some(xmltree::XMLNode::Element(element)) = to_visit.pop_front() {
-            for child in element.children.iter().rev() {
-                to_visit.push_front(child);
+/// Text and optional structured content of (a part of) an html document
+#[derive(Clone)]
+pub struct HtmlContent {
+    pub text: String,
+    pub blocks: Option<Vec<Block>>,
+    pub notes: Vec<Note>,
+    pub warnings: Vec<HtmlWarning>,
+}
+
+/// Problems of an html document that were recovered from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HtmlWarning {
+    MissingAnchor(String),
+    DuplicateId(String),
+    RecoveredHtml(String),
+}
+
+impl HtmlWarning {
+    pub fn into_parse_warning(self, path: &str) -> ParseWarning {
+        let path = path.to_string();
+        match self {
+            HtmlWarning::MissingAnchor(anchor) => ParseWarning::MissingAnchor { path, anchor },
+            HtmlWarning::DuplicateId(id) => ParseWarning::DuplicateId { path, id },
+            HtmlWarning::RecoveredHtml(error) => ParseWarning::RecoveredHtml { path, error },
+        }
+    }
+}
+
+impl HtmlContent {
+    /// join the contents of consecutive documents (or parts of documents)
+    pub fn join(contents: Vec<HtmlContent>, options: &ParseOptions) -> HtmlContent {
+        let separator = match options.text_format {
+            TextFormat::Flat => "\n",
+            TextFormat::Paragraphs => "\n\n",
+        };
+        let mut text = String::new();
+        let mut notes = Vec::new();
+        let mut warnings = Vec::new();
+        // character count of the joined text
+        let mut position = 0;
+        for (i, content) in contents.iter().enumerate() {
+            // empty paragraphs are left out, but their notes are kept
+            let skip_text =
+                options.text_format == TextFormat::Paragraphs && content.text.is_empty();
+            if !skip_text && i > 0 && (options.text_format == TextFormat::Flat || !text.is_empty())
+            {
+                text.push_str(separator);
+                position += separator.len();
             }
-            if get_named_anchor(element) == Some(start) {
-                break;
+            notes.extend(content.notes.iter().cloned().map(|mut note| {
+                note.position += position;
+                note
+            }));
+            text.push_str(&content.text);
+            position += content.text.chars().count();
+            warnings.extend(content.warnings.iter().cloned());
+        }
+        let blocks = if options.structured_content {
+            Some(
+                contents
+                    .into_iter()
+                    .flat_map(|content| content.blocks.unwrap_or_default())
+                    .collect(),
+            )
+        } else {
+            None
+        };
+        HtmlContent {
+            text,
+            blocks,
+            notes,
+            warnings,
+        }
+    }
+}
+
+/// Receives the nodes of an html document in document order
+pub trait ContentHandler {
+    fn start_element(&mut self, _element: &xmltree::Element) {}
+    fn end_element(&mut self, _element: &xmltree::Element) {}
+    fn text(&mut self, text: &str);
+}
+
+pub fn is_collapsible_whitespace(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\n' | '\r' | '\x0c')
+}
+
+/// Whether text in the language is written without spaces between words,
+/// so that line breaks in the source must not turn into spaces
+pub fn is_cjk_language(language: &str) -> bool {
+    let primary = language.split(['-', '_']).next().unwrap_or_default();
+    ["zh", "ja", "yue", "cmn", "lzh", "wuu", "hak", "nan"]
+        .iter()
+        .any(|cjk| primary.eq_ignore_ascii_case(cjk))
+}
+
+// characters of scripts that are written without spaces (Han, Kana, Bopomofo)
+// and their full width punctuation, Hangul uses spaces between words
+fn is_wide_char(c: char, cjk_language: bool) -> bool {
+    match c as u32 {
+        0x2E80..=0x2FDF // radicals
+        | 0x3000..=0x303F // symbols and punctuation
+        | 0x3040..=0x30FF // Hiragana and Katakana
+        | 0x3100..=0x312F // Bopomofo
+        | 0x31C0..=0x31FF // strokes and Katakana extensions
+        | 0x3400..=0x4DBF // Han extension A
+        | 0x4E00..=0x9FFF // Han
+        | 0xF900..=0xFAFF // compatibility ideographs
+        | 0xFF00..=0xFFEF // halfwidth and fullwidth forms
+        | 0x20000..=0x3FFFF => true, // Han extensions
+        // punctuation that is set full width in Chinese and Japanese text
+        0x2014 | 0x2015 | 0x2018 | 0x2019 | 0x201C | 0x201D | 0x2026 | 0x00B7 | 0x2022
+        | 0x203B => cjk_language,
+        _ => false,
+    }
+}
+
+/// Collapsed whitespace between two pieces of text
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+pub enum PendingWhitespace {
+    None,
+    /// spaces and tabs
+    Space,
+    /// line breaks in the source and boundaries of block-level elements
+    Break,
+}
+
+impl PendingWhitespace {
+    pub fn add(&mut self, whitespace: char) {
+        let added = match whitespace {
+            '\n' | '\r' => PendingWhitespace::Break,
+            _ => PendingWhitespace::Space,
+        };
+        *self = (*self).max(added);
+    }
+
+    /// Whether a space has to be inserted between the previous and the next character,
+    /// a break between two CJK characters is removed like browsers do
+    pub fn separates(self, previous: char, next: char, cjk_language: bool) -> bool {
+        match self {
+            PendingWhitespace::None => false,
+            PendingWhitespace::Space => true,
+            PendingWhitespace::Break => {
+                !(is_wide_char(previous, cjk_language) && is_wide_char(next, cjk_language))
             }
         }
     }
-    // do DFS for all nodes after start until encountering potential stop
-    // save all text that is encountered on the way
-    while let Some(next_node) = to_visit.pop_front() {
-        match next_node {
-            xmltree::XMLNode::Element(element) => {
-                // if stop anchor encountered, stop
-                // println!("{}", element.name);
-                if let (Some(stop), Some(name_attr)) = (stop_anchor, get_named_anchor(element)) {
-                    if stop == name_attr {
-                        break;
+
+    /// Like `separates`, but returns None if the decision depends on the next character
+    pub fn separates_any(self, previous: char, cjk_language: bool) -> Option<bool> {
+        match self {
+            PendingWhitespace::Break if is_wide_char(previous, cjk_language) => None,
+            PendingWhitespace::None => Some(false),
+            _ => Some(true),
+        }
+    }
+}
+
+// collects text on a single line, collapsing whitespace as done by browsers
+// text of different block-level elements and line breaks is separated by a space
+// unless both sides are CJK text
+// content that is not rendered (head, scripts, styles) is left out
+struct TextCollector {
+    text: String,
+    pending: PendingWhitespace,
+    cjk_language: bool,
+    // nesting depth within an element that is left out
+    skip_depth: usize,
+}
+
+impl TextCollector {
+    fn new(cjk_language: bool) -> Self {
+        TextCollector {
+            text: String::new(),
+            pending: PendingWhitespace::None,
+            cjk_language,
+            skip_depth: 0,
+        }
+    }
+}
+
+impl ContentHandler for TextCollector {
+    fn start_element(&mut self, element: &xmltree::Element) {
+        if self.skip_depth > 0 || is_skipped_element(element) {
+            self.skip_depth += 1;
+        } else if !is_inline_element(element) {
+            self.pending = PendingWhitespace::Break;
+        }
+    }
+
+    fn end_element(&mut self, element: &xmltree::Element) {
+        if self.skip_depth > 0 {
+            self.skip_depth -= 1;
+        } else if !is_inline_element(element) {
+            self.pending = PendingWhitespace::Break;
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        if self.skip_depth > 0 {
+            return;
+        }
+        for c in text.chars() {
+            if is_collapsible_whitespace(c) {
+                self.pending.add(c);
+            } else {
+                if let Some(previous) = self.text.chars().next_back() {
+                    if self.pending.separates(previous, c, self.cjk_language) {
+                        self.text.push(' ');
                     }
                 }
-                for child in element.children.iter().rev() {
-                    to_visit.push_front(child);
+                self.pending = PendingWhitespace::None;
+                self.text.push(c);
+            }
+        }
+    }
+}
+
+/// Get the contents of the parts of an html document that start at the given anchors
+/// (None for the beginning of the document), each part ends where the part
+/// of another anchor starts (or at the end of the document).
+/// The language of the document (`xml:lang`) or the given book language
+/// is used as a hint for joining CJK text
+///
+/// The document is parsed once and split at all anchors in the same traversal,
+/// the contents are returned in the order of the anchors.
+/// Problems of the whole document are returned with the first content
+pub fn html_to_contents(
+    full_text: &str,
+    anchors: &[Option<&str>],
+    language: Option<&str>,
+    options: &ParseOptions,
+) -> Vec<HtmlContent> {
+    let (root, html_error) = parse_html_recovering(full_text);
+    let mut warnings: Vec<HtmlWarning> = html_error
+        .into_iter()
+        .map(HtmlWarning::RecoveredHtml)
+        .collect();
+    let mut anchor_counts = HashMap::new();
+    count_anchors(&root, &mut anchor_counts);
+    for anchor in anchors.iter().flatten() {
+        if !anchor_counts.contains_key(anchor) {
+            warnings.push(HtmlWarning::MissingAnchor(anchor.to_string()));
+        }
+    }
+    let mut duplicate_ids: Vec<&str> = anchor_counts
+        .iter()
+        .filter(|(_anchor, count)| **count > 1)
+        .map(|(anchor, _count)| *anchor)
+        .collect();
+    duplicate_ids.sort_unstable();
+    warnings.extend(
+        duplicate_ids
+            .into_iter()
+            .map(|id| HtmlWarning::DuplicateId(id.to_string())),
+    );
+    let note_index = if options.extract_notes {
+        Some(NoteIndex::new(&root))
+    } else {
+        None
+    };
+    let cjk_language = root
+        .attributes
+        .get("lang")
+        .map(String::as_str)
+        .or(language)
+        .map(is_cjk_language)
+        .unwrap_or(false);
+    // paragraph formatted text is rendered from the block structure
+    // notes are the same for both walks
+    let mut notes: Vec<Vec<Note>> = Vec::new();
+    let blocks: Option<Vec<Vec<Block>>> =
+        if options.structured_content || options.text_format == TextFormat::Paragraphs {
+            let mut block_builders: Vec<BlockBuilder> = anchors
+                .iter()
+                .map(|_| BlockBuilder::new(cjk_language))
+                .collect();
+            notes = walk_html(
+                &root,
+                anchors,
+                &anchor_counts,
+                note_index.as_ref(),
+                &mut block_builders,
+            );
+            Some(
+                block_builders
+                    .into_iter()
+                    .map(BlockBuilder::finish)
+                    .collect(),
+            )
+        } else {
+            None
+        };
+    let texts: Vec<String> = match (options.text_format, &blocks) {
+        (TextFormat::Paragraphs, Some(blocks)) => {
+            blocks.iter().map(|blocks| blocks_to_text(blocks)).collect()
+        }
+        _ => {
+            let mut text_collectors: Vec<TextCollector> = anchors
+                .iter()
+                .map(|_| TextCollector::new(cjk_language))
+                .collect();
+            notes = walk_html(
+                &root,
+                anchors,
+                &anchor_counts,
+                note_index.as_ref(),
+                &mut text_collectors,
+            );
+            text_collectors
+                .into_iter()
+                .map(|text_collector| text_collector.text)
+                .collect()
+        }
+    };
+    let mut blocks = blocks.map(|blocks| blocks.into_iter());
+    texts
+        .into_iter()
+        .zip(notes)
+        .map(|(text, mut notes)| {
+            let mut blocks = blocks.as_mut().and_then(|blocks| blocks.next());
+            let text = if note_index.is_some() {
+                if let Some(blocks) = blocks.as_mut() {
+                    notes::strip_note_markers(blocks);
                 }
+                notes::resolve_note_positions(&text, &mut notes)
+            } else {
+                text
+            };
+            HtmlContent {
+                text,
+                blocks: blocks.filter(|_| options.structured_content),
+                notes,
+                warnings: std::mem::take(&mut warnings),
             }
-            xmltree::XMLNode::Text(s) => {
-                // println!("{}", s);
-                text.push(s.trim().to_string());
+        })
+        .collect()
+}
+
+/// Count the occurrences of all ids and named anchors
+pub fn count_anchors<'e>(
+    element: &'e xmltree::Element,
+    anchor_counts: &mut HashMap<&'e str, usize>,
+) {
+    if let Some(anchor) = get_named_anchor(element) {
+        *anchor_counts.entry(anchor).or_insert(0) += 1;
+    }
+    for child in element.children.iter().filter_map(|node| node.as_element()) {
+        count_anchors(child, anchor_counts);
+    }
+}
+
+/// Walk the html tree with depth first search, passing all nodes to the handler
+/// of the part of the document they belong to
+///
+/// Each part starts at its anchor (None for the beginning of the document)
+/// and ends where the part of another anchor starts, there is one handler per anchor.
+/// The anchors of the document are given by their occurrences, see [`count_anchors`].
+/// A part whose anchor is missing from the document starts at the beginning of the document,
+/// unless another part does, in which case it stays empty.
+/// If a part starts inside a block (at an inline anchor within a paragraph),
+/// the open elements are closed in the previous part,
+/// and the block and its inline elements are opened again in the new part.
+/// If notes are given, note markers and bodies are left out,
+/// and the notes of each part are returned in order,
+/// with their markers passed as [`NOTE_MARKER`]
+pub fn walk_html<'a, H: ContentHandler>(
+    root: &'a xmltree::Element,
+    anchors: &[Option<&'a str>],
+    anchor_counts: &HashMap<&str, usize>,
+    notes: Option<&'a NoteIndex>,
+    handlers: &'a mut [H],
+) -> Vec<Vec<Note>> {
+    let mut pending_anchors = HashMap::new();
+    for (part, anchor) in anchors.iter().enumerate() {
+        if let Some(anchor) = anchor {
+            pending_anchors.entry(*anchor).or_insert(part);
+        }
+    }
+    let current = anchors.iter().position(Option::is_none).or_else(|| {
+        anchors
+            .iter()
+            .position(|anchor| matches!(anchor, Some(a) if !anchor_counts.contains_key(a)))
+    });
+    let mut walker = HtmlWalker {
+        pending_anchors,
+        current,
+        open: Vec::new(),
+        note_index: notes,
+        note_depth: 0,
+        notes: anchors.iter().map(|_| Vec::new()).collect(),
+        handlers,
+    };
+    walker.visit_element(root);
+    walker.notes
+}
+
+struct HtmlWalker<'a, H> {
+    // parts by their anchor, for the parts that have not started yet
+    pending_anchors: HashMap<&'a str, usize>,
+    // part that the nodes currently belong to
+    current: Option<usize>,
+    // elements that were opened in the current part (or would have been, before the first part)
+    open: Vec<&'a xmltree::Element>,
+    note_index: Option<&'a NoteIndex>,
+    // number of open note markers and bodies, whose content is left out
+    note_depth: usize,
+    notes: Vec<Vec<Note>>,
+    handlers: &'a mut [H],
+}
+
+impl<'a, H: ContentHandler> HtmlWalker<'a, H> {
+    // the part receiving content, if any
+    fn emitting(&self) -> Option<usize> {
+        self.current.filter(|_| self.note_depth == 0)
+    }
+
+    // close the open elements in the current part, and if the anchor element is inline,
+    // open the innermost block with its inline elements in the new part
+    fn start_part(&mut self, part: usize, anchor_element: &xmltree::Element) {
+        if let Some(previous) = self.current {
+            for element in self.open.iter().rev() {
+                self.handlers[previous].end_element(element);
             }
+        }
+        let block_start = if is_inline_element(anchor_element) {
+            self.open
+                .iter()
+                .rposition(|element| !is_inline_element(element))
+                .unwrap_or(0)
+        } else {
+            self.open.len()
+        };
+        for element in &self.open[block_start..] {
+            self.handlers[part].start_element(element);
+        }
+        self.current = Some(part);
+    }
+
+    fn visit_element(&mut self, element: &'a xmltree::Element) {
+        if let Some(part) = get_named_anchor(element).and_then(|a| self.pending_anchors.remove(a)) {
+            self.start_part(part, element);
+        }
+        let note_element = self
+            .note_index
+            .and_then(|note_index| note_index.get_note_element(element));
+        let emitting = self.emitting();
+        let is_note_element = note_element.is_some();
+        match (note_element, emitting) {
+            (Some(NoteElement::Marker(note)), Some(part))
+            | (Some(NoteElement::Body(Some(note))), Some(part)) => {
+                let mut buf = [0; 4];
+                self.handlers[part].text(NOTE_MARKER.encode_utf8(&mut buf));
+                self.notes[part].push(note);
+            }
+            (None, Some(part)) => self.handlers[part].start_element(element),
             _ => {}
         }
+        let is_open = !is_note_element && self.note_depth == 0;
+        if is_note_element {
+            self.note_depth += 1;
+        } else if is_open {
+            self.open.push(element);
+        }
+        for child in &element.children {
+            match child {
+                xmltree::XMLNode::Element(child_element) => self.visit_element(child_element),
+                xmltree::XMLNode::Text(s) => {
+                    if let Some(part) = self.emitting() {
+                        self.handlers[part].text(s)
+                    }
+                }
+                _ => {}
+            }
+        }
+        if is_note_element {
+            self.note_depth -= 1;
+        } else if let Some(part) = self.emitting() {
+            self.handlers[part].end_element(element);
+        }
+        if is_open {
+            self.open.pop();
+        }
     }
-    Ok(text.join(" "))
 }
 
-#[allow(dead_code)]
-pub fn get_all_text(xml_node: &xmltree::XMLNode) -> String {
-    let mut text: Vec<String> = Vec::new();
-    match xml_node {
-        xmltree::XMLNode::Element(element) => {
-            for child in &element.children {
-                let s = get_all_text(child);
-                if !s.is_empty() {
-                    text.push(s);
-                }
+/// Parse an (X)HTML document
+///
+/// Content documents should be well-formed XHTML, but many are not.
+/// Documents that can not be parsed as XML are parsed with a lenient HTML5 parser instead,
+/// which recovers from errors such as unclosed tags or unescaped ampersands.
+pub fn parse_html(full_text: &str) -> xmltree::Element {
+    parse_html_recovering(full_text).0
+}
+
+/// Parse an (X)HTML document, also returning the XML error
+/// if the document had to be parsed leniently
+pub fn parse_html_recovering(full_text: &str) -> (xmltree::Element, Option<String>) {
+    // whitespace between inline elements is significant
+    let config = get_parser_config(full_text).whitespace_to_characters(true);
+    match xmltree::Element::parse_with_config(full_text.as_bytes(), config) {
+        Ok(root) => (root, None),
+        Err(err) => (parse_html_lenient(full_text), Some(err.to_string())),
+    }
+}
+
+fn parse_html_lenient(full_text: &str) -> xmltree::Element {
+    let dom = html5ever::parse_document(RcDom::default(), Default::default()).one(full_text);
+    // the HTML5 parser always creates an html root element
+    let root =
+        dom.document
+            .children
+            .borrow()
+            .iter()
+            .find_map(|child| match rcdom_to_xmltree(child) {
+                Some(xmltree::XMLNode::Element(element)) => Some(element),
+                _ => None,
+            });
+    root.unwrap_or_else(|| xmltree::Element::new("html"))
+}
+
+fn rcdom_to_xmltree(handle: &Handle) -> Option<xmltree::XMLNode> {
+    match &handle.data {
+        NodeData::Element { name, attrs, .. } => {
+            let mut element = xmltree::Element::new(&name.local);
+            element.namespace = Some(name.ns.to_string()).filter(|ns| !ns.is_empty());
+            for attr in attrs.borrow().iter() {
+                // attributes are keyed by local name, as done by the XML parser,
+                // but prefixes such as "epub:" are not namespace aware in HTML
+                let attr_name: &str = &attr.name.local;
+                let local_name = attr_name.rsplit(':').next().unwrap_or(attr_name);
+                element
+                    .attributes
+                    .insert(local_name.to_string(), attr.value.to_string());
             }
+            element.children = handle
+                .children
+                .borrow()
+                .iter()
+                .filter_map(rcdom_to_xmltree)
+                .collect();
+            Some(xmltree::XMLNode::Element(element))
         }
-        xmltree::XMLNode::Text(s) => {
-            return s.trim().to_string();
+        NodeData::Text { contents } => Some(xmltree::XMLNode::Text(contents.borrow().to_string())),
+        _ => None,
+    }
+}
+
+pub fn get_all_text(element: &xmltree::Element) -> String {
+    let mut text_collectors = [TextCollector::new(false)];
+    walk_html(
+        element,
+        &[None],
+        &HashMap::new(),
+        None,
+        &mut text_collectors,
+    );
+    let [text_collector] = text_collectors;
+    text_collector.text
+}
+
+/// get the text of the first heading of an html document,
+/// or the document title if there is no heading
+pub fn get_html_title(full_text: &str) -> Option<String> {
+    let root = parse_html(full_text);
+    let body_title = root
+        .get_child("body")
+        .and_then(find_first_heading)
+        .map(get_all_text);
+    let head_title = || {
+        root.get_child("head")
+            .and_then(|head| head.get_child("title"))
+            .and_then(|title| title.get_text())
+            .map(|text| text.trim().to_string())
+    };
+    body_title
+        .filter(|title| !title.is_empty())
+        .or_else(head_title)
+        .filter(|title| !title.is_empty())
+}
+
+/// Decode the percent-encoded bytes of an URL (path or fragment),
+/// invalid escapes are kept as they are
+pub fn percent_decode(url: &str) -> String {
+    let bytes = url.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
         }
-        _ => {}
     }
-    text.join(" ")
+    String::from_utf8(decoded).unwrap_or_else(|_| url.to_string())
+}
+
+/// get the source of the first image of an html document
+/// (an html image or an image within svg)
+pub fn get_first_image_src(full_text: &str) -> Option<String> {
+    let root = parse_html(full_text);
+    find_first_image_src(root.get_child("body")?).map(|src| src.to_string())
+}
+
+fn find_first_image_src(element: &xmltree::Element) -> Option<&str> {
+    element
+        .children
+        .iter()
+        .filter_map(|node| node.as_element())
+        .find_map(|child| match child.name.as_str() {
+            "img" => child.attributes.get("src").map(String::as_str),
+            // xlink:href of svg images
+            "image" => child.attributes.get("href").map(String::as_str),
+            _ => find_first_image_src(child),
+        })
+}
+
+fn find_first_heading(element: &xmltree::Element) -> Option<&xmltree::Element> {
+    element
+        .children
+        .iter()
+        .filter_map(|node| node.as_element())
+        .find_map(|child| {
+            if matches!(child.name.as_str(), "h1" | "h2" | "h3" | "h4" | "h5" | "h6") {
+                Some(child)
+            } else {
+                find_first_heading(child)
+            }
+        })
 }
 
 pub fn get_named_anchor(element: &xmltree::Element) -> Option<&str> {
@@ -81,12 +658,29 @@ pub fn get_named_anchor(element: &xmltree::Element) -> Option<&str> {
     result
 }
 
-pub fn get_parser_config() -> ParserConfig {
+/// Parser configuration for the given document,
+/// the named html entities are only added if the document uses any of them
+pub fn get_parser_config(text: &str) -> ParserConfig {
     let mut config = ParserConfig::new();
-    config.extra_entities = get_named_entities();
+    if uses_named_entities(text) {
+        config.extra_entities = get_named_entities().clone();
+    }
     config
 }
 
+// whether the text references entities other than the ones predefined by XML
+fn uses_named_entities(text: &str) -> bool {
+    text.split('&').skip(1).any(|rest| {
+        let name_len = rest
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .unwrap_or(rest.len());
+        let name = &rest[..name_len];
+        rest[name_len..].starts_with(';')
+            && name.starts_with(|c: char| c.is_ascii_alphabetic())
+            && !matches!(name, "amp" | "lt" | "gt" | "quot" | "apos")
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -96,42 +690,72 @@ mod tests {
     static SIMPLE_CHAPTER_HTML: &str = include_str!("../../test_resources/simple_chapter.html");
     static ENTITIES_CHAPTER_HTML: &str = include_str!("../../test_resources/chapter_entities.html");
 
+    // content starting at the start anchor (or beginning of document)
+    // and ending before the stop anchor (or end of document)
+    fn html_to_content(
+        full_text: &str,
+        start_anchor: Option<&str>,
+        stop_anchor: Option<&str>,
+        language: Option<&str>,
+        options: &ParseOptions,
+    ) -> HtmlContent {
+        let mut anchors = vec![start_anchor];
+        if stop_anchor.is_some() && stop_anchor != start_anchor {
+            anchors.push(stop_anchor);
+        }
+        html_to_contents(full_text, &anchors, language, options).swap_remove(0)
+    }
+
+    fn html_to_text(
+        full_text: &str,
+        start_anchor: Option<&str>,
+        stop_anchor: Option<&str>,
+    ) -> String {
+        html_to_content(
+            full_text,
+            start_anchor,
+            stop_anchor,
+            None,
+            &ParseOptions::default(),
+        )
+        .text
+    }
+
     #[test]
     fn get_all_text_simple() {
-        let root = xmltree::Element::parse(PRIDE_PREJUDICE_CHAPTER_HTML.as_bytes()).unwrap();
-        let all_text = get_all_text(&xmltree::XMLNode::Element(root));
+        let root = parse_html(PRIDE_PREJUDICE_CHAPTER_HTML);
+        let all_text = get_all_text(&root);
         // assert_eq!(all_text, "lala");
-        assert!(all_text
-            .starts_with("The Project Gutenberg eBook of Pride and Prejudice, by Jane Austen"));
+        // the document title in the head is not part of the text
+        assert!(all_text.starts_with("Chapter 3 Not all that Mrs. Bennet, however,"));
         assert!(all_text.contains("“I would not be so fastidious as you are,” cried Mr. Bingley, "));
         assert!(all_text.contains("You are dancing with the only handsome girl in the room,” said Mr. Darcy, looking at the eldest Miss Bennet."));
-        assert!(all_text.contains("and the Boulanger —” “If he had had any compassion for me ,” cried her husband impatiently,"));
+        assert!(all_text.contains("and the Boulanger—” “If he had had any compassion for me,” cried her husband impatiently,"));
     }
 
     #[test]
     fn html_to_text_no_anchors() {
-        let all_text = html_to_text(PRIDE_PREJUDICE_CHAPTER_HTML, None, None).unwrap();
+        let all_text = html_to_text(PRIDE_PREJUDICE_CHAPTER_HTML, None, None);
 
         // assert_eq!(all_text, "lala");
-        assert!(all_text
-            .starts_with("The Project Gutenberg eBook of Pride and Prejudice, by Jane Austen"));
+        // the document title in the head is not part of the text
+        assert!(all_text.starts_with("Chapter 3 Not all that Mrs. Bennet, however,"));
         assert!(all_text.contains("“I would not be so fastidious as you are,” cried Mr. Bingley, "));
         assert!(all_text.contains("You are dancing with the only handsome girl in the room,” said Mr. Darcy, looking at the eldest Miss Bennet."));
-        assert!(all_text.contains("and the Boulanger —” “If he had had any compassion for me ,” cried her husband impatiently,"));
+        assert!(all_text.contains("and the Boulanger—” “If he had had any compassion for me,” cried her husband impatiently,"));
     }
 
     #[test]
     fn html_to_text_with_anchors_simple() {
-        let all_text =
-            html_to_text(PRIDE_PREJUDICE_CHAPTER_HTML, Some("start"), Some("end")).unwrap();
+        let all_text = html_to_text(PRIDE_PREJUDICE_CHAPTER_HTML, Some("start"), Some("end"));
         assert!(all_text
             .starts_with("Mr. Bingley had soon made himself acquainted with all the principal people in the room;"));
-        assert!(all_text.ends_with("and the Boulanger —”"));
+        assert!(all_text.ends_with("and the Boulanger—”"));
     }
 
     #[test]
     fn html_to_text_with_start_anchor_only_pp() {
-        let all_text = html_to_text(PRIDE_PREJUDICE_CHAPTER_HTML, Some("start"), None).unwrap();
+        let all_text = html_to_text(PRIDE_PREJUDICE_CHAPTER_HTML, Some("start"), None);
         assert!(all_text
             .starts_with("Mr. Bingley had soon made himself acquainted with all the principal people in the room;"));
         assert!(all_text.ends_with("I quite detest the man.”"));
@@ -139,30 +763,210 @@ mod tests {
 
     #[test]
     fn html_to_text_with_start_anchor_only_simple() {
-        let all_text = html_to_text(SIMPLE_CHAPTER_HTML, Some("卷一-考城隍"), None).unwrap();
-        assert!(all_text.starts_with("卷一 考城隍 我姐夫的祖父，名叫宋焘，是本县的廪生。"));
+        let all_text = html_to_text(SIMPLE_CHAPTER_HTML, Some("卷一-考城隍"), None);
+        assert!(all_text.starts_with("卷一 考城隍我姐夫的祖父，名叫宋焘，是本县的廪生。"));
         assert!(all_text.ends_with("这里的记载只是个大概而已。"));
     }
 
     #[test]
     fn html_to_text_with_stop_anchor_only() {
-        let all_text = html_to_text(PRIDE_PREJUDICE_CHAPTER_HTML, None, Some("end")).unwrap();
-        assert!(all_text
-            .starts_with("The Project Gutenberg eBook of Pride and Prejudice, by Jane Austen"));
-        assert!(all_text.ends_with("and the Boulanger —”"));
+        let all_text = html_to_text(PRIDE_PREJUDICE_CHAPTER_HTML, None, Some("end"));
+        // the document title in the head is not part of the text
+        assert!(all_text.starts_with("Chapter 3 Not all that Mrs. Bennet, however,"));
+        assert!(all_text.ends_with("and the Boulanger—”"));
     }
 
     #[test]
     fn html_to_text_get_all_text_equal() {
-        let root = xmltree::Element::parse(PRIDE_PREJUDICE_CHAPTER_HTML.as_bytes()).unwrap();
-        let all_text1 = get_all_text(&xmltree::XMLNode::Element(root));
-        let all_text2 = html_to_text(PRIDE_PREJUDICE_CHAPTER_HTML, None, None).unwrap();
+        let root = parse_html(PRIDE_PREJUDICE_CHAPTER_HTML);
+        let all_text1 = get_all_text(&root);
+        let all_text2 = html_to_text(PRIDE_PREJUDICE_CHAPTER_HTML, None, None);
         assert_eq!(all_text1, all_text2);
     }
 
+    #[test]
+    fn html_to_text_inline_whitespace() {
+        let html = r#"<html><body><h1>Head<i>ing</i></h1>
+<p>wo<i>rd</i>s and <em>emphasis</em>, <b>bold</b> <span>span</span>
+  line<br/>break</p><p>next</p></body></html>"#;
+        let all_text = html_to_text(html, None, None);
+        assert_eq!(
+            "Heading words and emphasis, bold span line break next",
+            all_text
+        );
+    }
+
+    #[test]
+    fn html_to_text_cjk_joining() {
+        let html = r#"<html><body><h1>卷一 考城隍</h1><p>我姐夫的<i>祖父</i>，
+名叫宋焘。</p><p>ひらがな
+カタカナ and
+English</p><p>한국어
+텍스트</p></body></html>"#;
+        let all_text = html_to_text(html, None, None);
+        assert_eq!(
+            "卷一 考城隍我姐夫的祖父，名叫宋焘。ひらがなカタカナ and English 한국어 텍스트",
+            all_text
+        );
+    }
+
+    #[test]
+    fn html_to_text_cjk_ruby() {
+        let html = r#"<html><body><p><ruby>漢字<rp>（</rp><rt>かんじ</rt><rp>）</rp></ruby>を
+読む</p></body></html>"#;
+        assert_eq!("漢字を読む", html_to_text(html, None, None));
+    }
+
+    #[test]
+    fn html_to_text_cjk_language_punctuation() {
+        let html =
+            r#"<html xml:lang="zh-CN"><body><p>他说：</p><p>“请你去考试。”</p></body></html>"#;
+        assert_eq!("他说：“请你去考试。”", html_to_text(html, None, None));
+        let content = html_to_content(
+            &html.replace(r#" xml:lang="zh-CN""#, ""),
+            None,
+            None,
+            Some("en"),
+            &ParseOptions::default(),
+        );
+        assert_eq!("他说： “请你去考试。”", content.text);
+        let content = html_to_content(
+            "<html><body><p>……</p><p>“好”</p></body></html>",
+            None,
+            None,
+            Some("zh"),
+            &ParseOptions::default(),
+        );
+        assert_eq!("……“好”", content.text);
+    }
+
+    #[test]
+    fn html_to_text_malformed_html() {
+        let html = r#"<html><head><title>Title</title></head>
+<body><p id="start">Fish & Chips<br>for <i>two</p><p id="end">End</p></body></html>"#;
+        assert!(xmltree::Element::parse(html.as_bytes()).is_err());
+        let all_text = html_to_text(html, Some("start"), Some("end"));
+        assert_eq!("Fish & Chips for two", all_text);
+    }
+
+    #[test]
+    fn html_to_content_warnings() {
+        let html =
+            r#"<html><body><p id="a">one</p><p id="a">two</p><p id="b">three</p></body></html>"#;
+        let content = html_to_content(html, Some("b"), Some("c"), None, &ParseOptions::default());
+        assert_eq!("three", content.text);
+        assert_eq!(
+            vec![
+                HtmlWarning::MissingAnchor("c".to_string()),
+                HtmlWarning::DuplicateId("a".to_string())
+            ],
+            content.warnings
+        );
+    }
+
+    #[test]
+    fn html_to_text_skips_head_and_scripts() {
+        let html = r#"<html><head><title>T</title><style>p { color: red; }</style></head>
+<body><p>Body</p><script>var x = 1;</script></body></html>"#;
+        assert_eq!("Body", html_to_text(html, None, None));
+        let options = ParseOptions {
+            text_format: TextFormat::Paragraphs,
+            ..ParseOptions::default()
+        };
+        let content = html_to_content(html, None, None, None, &options);
+        assert_eq!("Body", content.text);
+    }
+
+    #[test]
+    fn html_to_contents_all_anchors() {
+        let html = r#"<html><body><p>intro</p><h2 id="c2">Two</h2><p>second</p>
+<h2 id="c1">One</h2><p>first</p><h2 id="c3">Three</h2><p>third</p></body></html>"#;
+        let texts: Vec<String> = html_to_contents(
+            html,
+            &[Some("c1"), None, Some("c3"), Some("c2")],
+            None,
+            &ParseOptions::default(),
+        )
+        .into_iter()
+        .map(|content| content.text)
+        .collect();
+        assert_eq!(
+            vec!["One first", "intro", "Three third", "Two second"],
+            texts
+        );
+    }
+
+    #[test]
+    fn html_to_contents_missing_anchor() {
+        let html = r#"<html><body><p>one</p><p id="b">two</p></body></html>"#;
+        let options = ParseOptions::default();
+        let contents = html_to_contents(html, &[Some("a"), Some("b")], None, &options);
+        assert_eq!("one", contents[0].text);
+        assert_eq!(
+            vec![HtmlWarning::MissingAnchor("a".to_string())],
+            contents[0].warnings
+        );
+        assert_eq!("two", contents[1].text);
+        // the beginning of the document belongs to another part
+        let contents = html_to_contents(html, &[None, Some("a")], None, &options);
+        assert_eq!("one two", contents[0].text);
+        assert_eq!("", contents[1].text);
+    }
+
+    #[test]
+    fn html_to_contents_anchor_within_block() {
+        let html = r#"<html><body><blockquote><p>one <i>two <span id="b"/>three</i> four</p>
+<p>five</p></blockquote></body></html>"#;
+        let options = ParseOptions {
+            text_format: TextFormat::Paragraphs,
+            ..ParseOptions::default()
+        };
+        let texts: Vec<String> = html_to_contents(html, &[None, Some("b")], None, &options)
+            .into_iter()
+            .map(|content| content.text)
+            .collect();
+        assert_eq!(vec!["one two", "three four\n\nfive"], texts);
+    }
+
+    #[test]
+    fn parse_html_lenient_epub_attributes() {
+        let html = r#"<html><body><section epub:type="chapter" id="ch1"><p>unclosed</section></body></html>"#;
+        let root = parse_html(html);
+        let section = root
+            .get_child("body")
+            .unwrap()
+            .get_child("section")
+            .unwrap();
+        assert_eq!(Some(&"chapter".to_string()), section.attributes.get("type"));
+        assert_eq!(Some("ch1"), get_named_anchor(section));
+    }
+
+    #[test]
+    fn get_html_title_heading_or_title() {
+        assert_eq!(
+            Some("卷一 考城隍".to_string()),
+            get_html_title(SIMPLE_CHAPTER_HTML)
+        );
+        let no_heading = "<html><head><title> Title </title></head><body><p>text</p></body></html>";
+        assert_eq!(Some("Title".to_string()), get_html_title(no_heading));
+        let no_title = "<html><head></head><body><p>text</p></body></html>";
+        assert_eq!(None, get_html_title(no_title));
+    }
+
     #[test]
     fn html_to_text_with_entitites() {
         // just check if no error is thrown due to unknown entity
         let _all_text = html_to_text(ENTITIES_CHAPTER_HTML, None, None);
     }
+
+    #[test]
+    fn named_entities_only_when_used() {
+        assert!(uses_named_entities(ENTITIES_CHAPTER_HTML));
+        assert!(uses_named_entities("<p>a&nbsp;b</p>"));
+        assert!(!uses_named_entities(
+            "<p>a &amp; b &#160; &#x2014; &lt;</p>"
+        ));
+        assert!(!uses_named_entities("<p>a & b; c&d</p>"));
+        assert!(get_parser_config("<p>&amp;</p>").extra_entities.is_empty());
+    }
 }
@@ -0,0 +1,639 @@
+use std::collections::HashSet;
+use std::io::{Cursor, Seek, Write};
+
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    errors::WriteError,
+    types::{Block, Book, Chapter, Contributor, Inline, Note},
+};
+
+const CONTENT_OPF_PATH: &str = "OEBPS/content.opf";
+// paths relative to the package document
+const NAV_HREF: &str = "nav.xhtml";
+const NCX_HREF: &str = "toc.ncx";
+const PREFACE_HREF: &str = "text/preface.xhtml";
+const PREFACE_ID: &str = "preface";
+
+const CONTAINER_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>
+"#;
+
+// a chapter with the href of its content document and its nested chapters
+struct ChapterFile<'b> {
+    chapter: &'b Chapter,
+    // the content is wrapped in a section with this id, which the table of contents points to,
+    // so that the document title is not part of the chapter text
+    id: String,
+    href: String,
+    // position of the chapter in reading order (1-based)
+    number: usize,
+    children: Vec<ChapterFile<'b>>,
+}
+
+impl ChapterFile<'_> {
+    fn src(&self) -> String {
+        format!("{}#{}", self.href, self.id)
+    }
+
+    // the chapter title, or a generated label for untitled chapters,
+    // as empty links and labels are not allowed in the table of contents
+    fn label(&self) -> String {
+        if self.chapter.title.trim().is_empty() {
+            format!("Chapter {}", self.number)
+        } else {
+            self.chapter.title.clone()
+        }
+    }
+}
+
+/// Write a book as Epub 3 file (with Epub 2 table of contents for older reading systems)
+///
+/// Each chapter and subchapter is written to its own content document,
+/// using its structured content if present and its text otherwise
+pub fn book_to_epub(book: &Book) -> Result<Vec<u8>, WriteError> {
+    Ok(write_epub(book, Cursor::new(Vec::new()))?.into_inner())
+}
+
+/// Write a book as Epub 3 file to the given writer, see [`book_to_epub`]
+pub fn write_epub<W: Write + Seek>(book: &Book, writer: W) -> Result<W, WriteError> {
+    let language = book
+        .metadata
+        .languages
+        .first()
+        .map(String::as_str)
+        .unwrap_or("en");
+    let identifier = get_identifier(book);
+    let mut counter = 0;
+    let chapter_files = to_chapter_files(&book.chapters, &mut counter);
+    let has_preface = !book.preface_content.is_empty() || book.preface_blocks.is_some();
+
+    let mut zip = ZipWriter::new(writer);
+    // the mimetype must be the first file, stored uncompressed
+    zip.start_file(
+        "mimetype",
+        FileOptions::default().compression_method(CompressionMethod::Stored),
+    )?;
+    zip.write_all(b"application/epub+zip")?;
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    zip.start_file("META-INF/container.xml", options)?;
+    zip.write_all(CONTAINER_XML.as_bytes())?;
+    zip.start_file(CONTENT_OPF_PATH, options)?;
+    zip.write_all(
+        content_opf(book, &identifier, language, has_preface, &chapter_files).as_bytes(),
+    )?;
+    zip.start_file(format!("OEBPS/{}", NAV_HREF), options)?;
+    zip.write_all(nav_doc(book, language, &chapter_files).as_bytes())?;
+    zip.start_file(format!("OEBPS/{}", NCX_HREF), options)?;
+    zip.write_all(toc_ncx(book, &identifier, &chapter_files).as_bytes())?;
+    if has_preface {
+        let body = body_html(
+            PREFACE_ID,
+            &book.preface_content,
+            book.preface_blocks.as_deref(),
+            &book.preface_notes,
+        );
+        zip.start_file(format!("OEBPS/{}", PREFACE_HREF), options)?;
+        zip.write_all(xhtml(&book.title, language, &body).as_bytes())?;
+    }
+    for file in flatten(&chapter_files) {
+        let chapter = file.chapter;
+        let body = body_html(
+            &file.id,
+            &chapter.text,
+            chapter.blocks.as_deref(),
+            &chapter.notes,
+        );
+        zip.start_file(format!("OEBPS/{}", file.href), options)?;
+        zip.write_all(xhtml(&file.label(), language, &body).as_bytes())?;
+    }
+    Ok(zip.finish()?)
+}
+
+fn to_chapter_files<'b>(chapters: &'b [Chapter], counter: &mut usize) -> Vec<ChapterFile<'b>> {
+    chapters
+        .iter()
+        .map(|chapter| {
+            *counter += 1;
+            ChapterFile {
+                chapter,
+                id: format!("chapter-{}", counter),
+                href: format!("text/chapter_{:03}.xhtml", counter),
+                number: *counter,
+                children: to_chapter_files(&chapter.subchapters, counter),
+            }
+        })
+        .collect()
+}
+
+// chapter files in reading order
+fn flatten<'f, 'b>(files: &'f [ChapterFile<'b>]) -> Vec<&'f ChapterFile<'b>> {
+    files
+        .iter()
+        .flat_map(|file| std::iter::once(file).chain(flatten(&file.children)))
+        .collect()
+}
+
+fn get_depth(files: &[ChapterFile]) -> usize {
+    files
+        .iter()
+        .map(|file| 1 + get_depth(&file.children))
+        .max()
+        .unwrap_or(0)
+}
+
+// the book's unique identifier, or an uuid derived from title and author
+fn get_identifier(book: &Book) -> String {
+    if let Some(identifier) = book
+        .metadata
+        .unique_identifier
+        .as_ref()
+        .or_else(|| book.metadata.identifiers.first().map(|id| &id.value))
+    {
+        return identifier.clone();
+    }
+    let name = format!("{}\n{}", book.title, book.author.as_deref().unwrap_or(""));
+    let hex = sha1_smol::Sha1::from(name).digest().to_string();
+    format!(
+        "urn:uuid:{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+// Epub 3 requires the modification date as CCYY-MM-DDThh:mm:ssZ,
+// the current time is not available on all targets (e.g. wasm32-unknown-unknown)
+fn get_modified(book: &Book) -> &str {
+    book.metadata
+        .dates
+        .iter()
+        .filter(|date| date.event.as_deref() == Some("modification"))
+        .map(|date| date.value.as_str())
+        .find(|value| value.len() == 20 && value.ends_with('Z') && value.get(10..11) == Some("T"))
+        .unwrap_or("1970-01-01T00:00:00Z")
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// the primary creators, or the author if the metadata has none
+fn get_creators(book: &Book) -> Vec<Contributor> {
+    if !book.metadata.creators.is_empty() {
+        return book.metadata.creators.clone();
+    }
+    book.author
+        .iter()
+        .map(|author| Contributor {
+            name: author.clone(),
+            roles: vec!["aut".to_string()],
+            file_as: None,
+            display_seq: None,
+        })
+        .collect()
+}
+
+fn contributor_xml(element: &str, id: &str, contributor: &Contributor) -> String {
+    let mut xml = format!(
+        "    <dc:{} id=\"{}\">{}</dc:{}>\n",
+        element,
+        id,
+        escape(&contributor.name),
+        element
+    );
+    for role in &contributor.roles {
+        xml.push_str(&format!(
+            "    <meta refines=\"#{}\" property=\"role\" scheme=\"marc:relators\">{}</meta>\n",
+            id,
+            escape(role)
+        ));
+    }
+    if let Some(file_as) = &contributor.file_as {
+        xml.push_str(&format!(
+            "    <meta refines=\"#{}\" property=\"file-as\">{}</meta>\n",
+            id,
+            escape(file_as)
+        ));
+    }
+    if let Some(display_seq) = contributor.display_seq {
+        xml.push_str(&format!(
+            "    <meta refines=\"#{}\" property=\"display-seq\">{}</meta>\n",
+            id, display_seq
+        ));
+    }
+    xml
+}
+
+fn metadata_xml(book: &Book, identifier: &str, language: &str) -> String {
+    let metadata = &book.metadata;
+    let mut xml = format!(
+        "    <dc:identifier id=\"book-id\">{}</dc:identifier>\n    <dc:title>{}</dc:title>\n    <dc:language>{}</dc:language>\n",
+        escape(identifier),
+        escape(&book.title),
+        escape(language)
+    );
+    for (i, creator) in get_creators(book).iter().enumerate() {
+        xml.push_str(&contributor_xml(
+            "creator",
+            &format!("creator-{}", i + 1),
+            creator,
+        ));
+    }
+    for (i, contributor) in metadata.contributors.iter().enumerate() {
+        xml.push_str(&contributor_xml(
+            "contributor",
+            &format!("contributor-{}", i + 1),
+            contributor,
+        ));
+    }
+    let elements = [
+        ("publisher", metadata.publisher.as_deref()),
+        ("date", metadata.publication_date()),
+        ("description", metadata.description.as_deref()),
+        ("rights", metadata.rights.as_deref()),
+        ("source", metadata.source.as_deref()),
+    ];
+    let subjects = metadata
+        .subjects
+        .iter()
+        .map(|subject| ("subject", Some(subject.as_str())));
+    for (element, value) in elements.iter().copied().chain(subjects) {
+        if let Some(value) = value {
+            xml.push_str(&format!(
+                "    <dc:{}>{}</dc:{}>\n",
+                element,
+                escape(value),
+                element
+            ));
+        }
+    }
+    xml.push_str(&format!(
+        "    <meta property=\"dcterms:modified\">{}</meta>\n",
+        get_modified(book)
+    ));
+    xml
+}
+
+fn content_opf(
+    book: &Book,
+    identifier: &str,
+    language: &str,
+    has_preface: bool,
+    chapter_files: &[ChapterFile],
+) -> String {
+    let mut manifest = format!(
+        "    <item id=\"nav\" href=\"{}\" media-type=\"application/xhtml+xml\" properties=\"nav\"/>\n    <item id=\"ncx\" href=\"{}\" media-type=\"application/x-dtbncx+xml\"/>\n",
+        NAV_HREF, NCX_HREF
+    );
+    let mut spine = String::new();
+    let mut add_item = |id: &str, href: &str| {
+        manifest.push_str(&format!(
+            "    <item id=\"{}\" href=\"{}\" media-type=\"application/xhtml+xml\"/>\n",
+            id, href
+        ));
+        spine.push_str(&format!("    <itemref idref=\"{}\"/>\n", id));
+    };
+    if has_preface {
+        add_item(PREFACE_ID, PREFACE_HREF);
+    }
+    for file in flatten(chapter_files) {
+        add_item(&file.id, &file.href);
+    }
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="book-id">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+{}  </metadata>
+  <manifest>
+{}  </manifest>
+  <spine toc="ncx">
+{}  </spine>
+</package>
+"#,
+        metadata_xml(book, identifier, language),
+        manifest,
+        spine
+    )
+}
+
+fn nav_list(files: &[ChapterFile]) -> String {
+    let items: String = files
+        .iter()
+        .map(|file| {
+            let children = if file.children.is_empty() {
+                String::new()
+            } else {
+                nav_list(&file.children)
+            };
+            format!(
+                "<li><a href=\"{}\">{}</a>{}</li>",
+                file.src(),
+                escape(&file.label()),
+                children
+            )
+        })
+        .collect();
+    format!("<ol>{}</ol>", items)
+}
+
+fn nav_doc(book: &Book, language: &str, chapter_files: &[ChapterFile]) -> String {
+    let body = format!(
+        "<nav epub:type=\"toc\" id=\"toc\"><h1>{}</h1>{}</nav>\n",
+        escape(&book.title),
+        nav_list(chapter_files)
+    );
+    xhtml(&book.title, language, &body)
+}
+
+fn nav_points(files: &[ChapterFile], play_order: &mut usize) -> String {
+    files
+        .iter()
+        .map(|file| {
+            *play_order += 1;
+            let id = format!("navpoint-{}", play_order);
+            let current_play_order = *play_order;
+            format!(
+                "<navPoint id=\"{}\" playOrder=\"{}\"><navLabel><text>{}</text></navLabel><content src=\"{}\"/>{}</navPoint>\n",
+                id,
+                current_play_order,
+                escape(&file.label()),
+                file.src(),
+                nav_points(&file.children, play_order)
+            )
+        })
+        .collect()
+}
+
+fn toc_ncx(book: &Book, identifier: &str, chapter_files: &[ChapterFile]) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+<head>
+<meta name="dtb:uid" content="{}"/>
+<meta name="dtb:depth" content="{}"/>
+</head>
+<docTitle><text>{}</text></docTitle>
+<navMap>
+{}</navMap>
+</ncx>
+"#,
+        escape(identifier),
+        get_depth(chapter_files).max(1),
+        escape(&book.title),
+        nav_points(chapter_files, &mut 0)
+    )
+}
+
+fn xhtml(title: &str, language: &str, body: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops" xml:lang="{lang}" lang="{lang}">
+<head>
+<meta charset="utf-8"/>
+<title>{}</title>
+</head>
+<body>
+{}</body>
+</html>
+"#,
+        escape(title),
+        body,
+        lang = escape(language)
+    )
+}
+
+// content of a chapter, followed by its notes, in a section with the given id
+//
+// notes without id (or with an id that is already taken) get a generated id
+// that is unique within the document
+fn body_html(section_id: &str, text: &str, blocks: Option<&[Block]>, notes: &[Note]) -> String {
+    let mut html = format!("<section id=\"{}\">\n", escape(section_id));
+    match blocks {
+        Some(blocks) => write_blocks(&mut html, blocks),
+        None => write_text(&mut html, text),
+    }
+    let mut taken_ids: HashSet<&str> = notes.iter().map(|note| note.id.as_str()).collect();
+    taken_ids.insert(section_id);
+    let mut written_ids = HashSet::new();
+    let mut counter = 0;
+    for note in notes {
+        let id = if !note.id.is_empty() && note.id != section_id && !written_ids.contains(&note.id)
+        {
+            note.id.clone()
+        } else {
+            loop {
+                counter += 1;
+                let id = format!("note-{}", counter);
+                if !taken_ids.contains(id.as_str()) && !written_ids.contains(&id) {
+                    break id;
+                }
+            }
+        };
+        html.push_str(&format!(
+            "<aside epub:type=\"footnote\" id=\"{}\"><p>{}</p></aside>\n",
+            escape(&id),
+            escape(&note.text)
+        ));
+        written_ids.insert(id);
+    }
+    html.push_str("</section>\n");
+    html
+}
+
+// paragraphs are separated by blank lines, line breaks are kept
+fn write_text(html: &mut String, text: &str) {
+    for paragraph in text
+        .split("\n\n")
+        .map(str::trim)
+        .filter(|paragraph| !paragraph.is_empty())
+    {
+        html.push_str(&format!(
+            "<p>{}</p>\n",
+            escape(paragraph).replace('\n', "<br/>")
+        ));
+    }
+}
+
+fn write_blocks(html: &mut String, blocks: &[Block]) {
+    for block in blocks {
+        match block {
+            Block::Paragraph { content } => {
+                html.push_str("<p>");
+                write_inlines(html, content);
+                html.push_str("</p>\n");
+            }
+            Block::Heading { level, content } => {
+                let level = (*level).clamp(1, 6);
+                html.push_str(&format!("<h{}>", level));
+                write_inlines(html, content);
+                html.push_str(&format!("</h{}>\n", level));
+            }
+            Block::List { ordered, items } => {
+                let tag = if *ordered { "ol" } else { "ul" };
+                html.push_str(&format!("<{}>\n", tag));
+                for item in items {
+                    html.push_str("<li>");
+                    write_blocks(html, item);
+                    html.push_str("</li>\n");
+                }
+                html.push_str(&format!("</{}>\n", tag));
+            }
+            Block::Quote { blocks } => {
+                html.push_str("<blockquote>\n");
+                write_blocks(html, blocks);
+                html.push_str("</blockquote>\n");
+            }
+            Block::Preformatted { text } => {
+                html.push_str(&format!("<pre>{}</pre>\n", escape(text)));
+            }
+            Block::Table { rows } => {
+                html.push_str("<table>\n");
+                for row in rows {
+                    html.push_str("<tr>");
+                    for cell in row {
+                        html.push_str("<td>");
+                        write_inlines(html, cell);
+                        html.push_str("</td>");
+                    }
+                    html.push_str("</tr>\n");
+                }
+                html.push_str("</table>\n");
+            }
+        }
+    }
+}
+
+fn write_inlines(html: &mut String, content: &[Inline]) {
+    for inline in content {
+        match inline {
+            Inline::Text { text } => html.push_str(&escape(text)),
+            Inline::Emphasis { content } => {
+                html.push_str("<em>");
+                write_inlines(html, content);
+                html.push_str("</em>");
+            }
+            Inline::Strong { content } => {
+                html.push_str("<strong>");
+                write_inlines(html, content);
+                html.push_str("</strong>");
+            }
+            Inline::Link { href, content } => {
+                html.push_str(&format!("<a href=\"{}\">", escape(href)));
+                write_inlines(html, content);
+                html.push_str("</a>");
+            }
+            Inline::LineBreak => html.push_str("<br/>"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Finding, ParseOptions};
+    use crate::{epub_to_book, epub_to_book_with_options, EpubArchive};
+
+    static EPUB_NESTED: &[u8] = include_bytes!("../../test_resources/nested.epub");
+    static EPUB_PAID_OFF: &[u8] = include_bytes!("../../test_resources/paid_off.epub");
+
+    fn assert_same_chapters(expected: &[Chapter], actual: &[Chapter]) {
+        assert_eq!(expected.len(), actual.len());
+        for (expected, actual) in expected.iter().zip(actual) {
+            assert_eq!(expected.title, actual.title);
+            assert_eq!(expected.text, actual.text);
+            assert_same_chapters(&expected.subchapters, &actual.subchapters);
+        }
+    }
+
+    #[test]
+    fn write_nested_book() {
+        let book = epub_to_book(EPUB_NESTED).unwrap();
+        let bytes = book_to_epub(&book).unwrap();
+        let archive = EpubArchive::new(&bytes).unwrap();
+        assert_eq!(Vec::<Finding>::new(), archive.validate());
+        let written = archive.to_book().unwrap();
+        assert_eq!(book.title, written.title);
+        assert_eq!(book.author, written.author);
+        assert_eq!(book.preface_content, written.preface_content);
+        assert_eq!(
+            book.metadata.unique_identifier,
+            written.metadata.unique_identifier
+        );
+        assert_same_chapters(&book.chapters, &written.chapters);
+    }
+
+    #[test]
+    fn write_untitled_chapter() {
+        let mut book = epub_to_book(EPUB_NESTED).unwrap();
+        book.chapters[1].title = String::new();
+        let bytes = book_to_epub(&book).unwrap();
+        let archive = EpubArchive::new(&bytes).unwrap();
+        assert_eq!(Vec::<Finding>::new(), archive.validate());
+        let written = archive.to_book().unwrap();
+        // chapters are numbered in reading order, including the subchapters of the first one
+        assert_eq!("Chapter 6", written.chapters[1].title);
+        assert_eq!(book.chapters[1].text, written.chapters[1].text);
+    }
+
+    #[test]
+    fn unique_note_ids() {
+        let note = |id: &str| Note {
+            id: id.to_string(),
+            marker_id: None,
+            marker: String::new(),
+            position: 0,
+            text: "Note".to_string(),
+        };
+        let notes = [
+            note(""),
+            note("note-1"),
+            note("note-1"),
+            note("chapter-1"),
+            note(""),
+        ];
+        let html = body_html("chapter-1", "Text", None, &notes);
+        let ids: Vec<&str> = html
+            .split("id=\"")
+            .skip(1)
+            .map(|rest| rest.split('"').next().unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                "chapter-1",
+                "note-2",
+                "note-1",
+                "note-3",
+                "note-4",
+                "note-5"
+            ],
+            ids
+        );
+    }
+
+    #[test]
+    fn write_structured_book() {
+        let options = ParseOptions {
+            structured_content: true,
+            ..Default::default()
+        };
+        let book = epub_to_book_with_options(EPUB_PAID_OFF, &options).unwrap();
+        let bytes = book_to_epub(&book).unwrap();
+        let written = epub_to_book_with_options(&bytes, &options).unwrap();
+        assert_eq!(book.chapters.len(), written.chapters.len());
+        for (expected, actual) in book.chapters.iter().zip(&written.chapters) {
+            assert_eq!(expected.title, actual.title);
+            assert_eq!(expected.blocks, actual.blocks);
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use epubparse::epub_to_book;
+use epubparse::{epub_to_book, validate};
 use wasm_bindgen::prelude::*;
 
 /// returns either
@@ -10,3 +10,9 @@ pub fn parse_epub(bytes: &[u8]) -> Result<JsValue, JsValue> {
     book.map(|b| serde_wasm_bindgen::to_value(&b).unwrap())
         .map_err(|parse_error| JsValue::from_str(&parse_error.to_string()))
 }
+
+/// returns the validation findings converted to JsValue
+#[wasm_bindgen]
+pub fn validate_epub(bytes: &[u8]) -> JsValue {
+    serde_wasm_bindgen::to_value(&validate(bytes)).unwrap()
+}